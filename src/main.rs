@@ -1,4 +1,4 @@
-use paste::paste;
+#![allow(clippy::upper_case_acronyms)]
 
 trait FlagsRegisterPair {
     fn set_from_u16(&mut self, value: u16);
@@ -27,14 +27,19 @@ impl FlagsRegisterPair for FlagsRegister {
 
 macro_rules! register_pair {
     ($high_reg:ident, $low_reg:ident) => {
+        register_pair!($high_reg, $low_reg, 0xFF);
+    };
+    // $low_mask keeps bits that don't exist in hardware (F's low nibble) out
+    // of the pair, whatever type backs the low register
+    ($high_reg:ident, $low_reg:ident, $low_mask:expr) => {
         paste::item! {
         fn [<get_ $high_reg $low_reg>](&self) -> u16 {
-            (self.$high_reg as u16) << 8 | self.$low_reg.get_u16()
+            (self.$high_reg as u16) << 8 | (self.$low_reg.get_u16() & $low_mask)
         }
 
         fn [<set_ $high_reg $low_reg>](&mut self, value: u16) {
             self.$high_reg = ((value & 0xFF00) >> 8) as u8;
-            self.$low_reg.set_from_u16(value & 0xFF);
+            self.$low_reg.set_from_u16(value & $low_mask);
         }
         }
     };
@@ -53,13 +58,13 @@ struct Registers {
 }
 
 impl Registers {
-    register_pair!(a, f);
+    register_pair!(a, f, 0xF0);
     register_pair!(b, c);
     register_pair!(d, e);
     register_pair!(h, l);
 }
 
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
 struct FlagsRegister {
     zero: bool,
     subtract: bool,
@@ -76,10 +81,11 @@ impl FlagsRegister {
 
 impl std::convert::From<FlagsRegister> for u8 {
     fn from(flag: FlagsRegister) -> u8 {
-        (if flag.zero { 1 } else { 0 }) << FlagsRegister::ZERO_FLAG_BYTE_POSITION
-            | (if flag.subtract { 1 } else { 0 }) << FlagsRegister::SUBTRACT_FLAG_BYTE_POSITION
-            | (if flag.half_carry { 1 } else { 0 }) << FlagsRegister::HALF_CARRY_FLAG_BYTE_POSITION
-            | (if flag.carry { 1 } else { 0 }) << FlagsRegister::CARRY_FLAG_BYTE_POSITION
+        ((if flag.zero { 1 } else { 0 }) << FlagsRegister::ZERO_FLAG_BYTE_POSITION)
+            | ((if flag.subtract { 1 } else { 0 }) << FlagsRegister::SUBTRACT_FLAG_BYTE_POSITION)
+            | ((if flag.half_carry { 1 } else { 0 })
+                << FlagsRegister::HALF_CARRY_FLAG_BYTE_POSITION)
+            | ((if flag.carry { 1 } else { 0 }) << FlagsRegister::CARRY_FLAG_BYTE_POSITION)
     }
 }
 
@@ -99,6 +105,43 @@ impl std::convert::From<u8> for FlagsRegister {
     }
 }
 
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+enum Flag {
+    Zero,
+    Subtract,
+    HalfCarry,
+    Carry,
+}
+
+impl std::convert::From<Flag> for FlagsRegister {
+    fn from(flag: Flag) -> Self {
+        FlagsRegister::default() | flag
+    }
+}
+
+impl std::ops::BitOr<Flag> for FlagsRegister {
+    type Output = FlagsRegister;
+
+    fn bitor(mut self, flag: Flag) -> FlagsRegister {
+        match flag {
+            Flag::Zero => self.zero = true,
+            Flag::Subtract => self.subtract = true,
+            Flag::HalfCarry => self.half_carry = true,
+            Flag::Carry => self.carry = true,
+        }
+        self
+    }
+}
+
+impl std::ops::BitOr for Flag {
+    type Output = FlagsRegister;
+
+    fn bitor(self, other: Flag) -> FlagsRegister {
+        FlagsRegister::from(self) | other
+    }
+}
+
 enum Instruction {
     ADD(ArithmeticTarget),
     ADDHL(ADDHLTarget),
@@ -111,13 +154,137 @@ enum Instruction {
     CP(ArithmeticTarget), // same as SUB but without storing the result
     INC(ArithmeticTarget),
     DEC(ArithmeticTarget),
+    INC16(WordTarget),
+    DEC16(WordTarget),
+    NOP(),
+    HALT(),
+    CPL(),
     CCF(),
     SCF(),
     RRA(),
     RLA(),
     RRCA(),
+    RLCA(),
+    DAA(),
+    // 0xCB-prefixed instructions
+    BIT(u8, ArithmeticTarget),
+    SET(u8, ArithmeticTarget),
+    RES(u8, ArithmeticTarget),
+    RLC(ArithmeticTarget),
+    RRC(ArithmeticTarget),
+    RL(ArithmeticTarget),
+    RR(ArithmeticTarget),
+    SLA(ArithmeticTarget),
+    SRA(ArithmeticTarget),
+    SWAP(ArithmeticTarget),
+    SRL(ArithmeticTarget),
+    PUSH(StackTarget),
+    POP(StackTarget),
+    JP(JumpCondition), // to a 16-bit immediate address
+    JR(JumpCondition), // by a signed 8-bit immediate offset
+    LD(ArithmeticTarget, LoadSource),
+    LD16(WordTarget), // from a 16-bit immediate
+    EI(),
+    DI(),
+    RETI(),
+}
+
+impl Instruction {
+    fn from_byte(opcode: u8) -> Option<Instruction> {
+        match opcode {
+            0x80..=0x87 => Some(Instruction::ADD(ArithmeticTarget::from_bits(opcode))),
+            0x88..=0x8F => Some(Instruction::ADC(ArithmeticTarget::from_bits(opcode))),
+            0x90..=0x97 => Some(Instruction::SUB(ArithmeticTarget::from_bits(opcode))),
+            0x98..=0x9F => Some(Instruction::SBC(ArithmeticTarget::from_bits(opcode))),
+            0xA0..=0xA7 => Some(Instruction::AND(ArithmeticTarget::from_bits(opcode))),
+            0xA8..=0xAF => Some(Instruction::XOR(ArithmeticTarget::from_bits(opcode))),
+            0xB0..=0xB7 => Some(Instruction::OR(ArithmeticTarget::from_bits(opcode))),
+            0xB8..=0xBF => Some(Instruction::CP(ArithmeticTarget::from_bits(opcode))),
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+                Some(Instruction::INC(ArithmeticTarget::from_bits(opcode >> 3)))
+            }
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+                Some(Instruction::DEC(ArithmeticTarget::from_bits(opcode >> 3)))
+            }
+            0x03 => Some(Instruction::INC16(WordTarget::BC)),
+            0x13 => Some(Instruction::INC16(WordTarget::DE)),
+            0x23 => Some(Instruction::INC16(WordTarget::HL)),
+            0x33 => Some(Instruction::INC16(WordTarget::SP)),
+            0x0B => Some(Instruction::DEC16(WordTarget::BC)),
+            0x1B => Some(Instruction::DEC16(WordTarget::DE)),
+            0x2B => Some(Instruction::DEC16(WordTarget::HL)),
+            0x3B => Some(Instruction::DEC16(WordTarget::SP)),
+            0x09 => Some(Instruction::ADDHL(ADDHLTarget::BC)),
+            0x19 => Some(Instruction::ADDHL(ADDHLTarget::DE)),
+            0x29 => Some(Instruction::ADDHL(ADDHLTarget::HL)),
+            0x07 => Some(Instruction::RLCA()),
+            0x0F => Some(Instruction::RRCA()),
+            0x17 => Some(Instruction::RLA()),
+            0x1F => Some(Instruction::RRA()),
+            0x00 => Some(Instruction::NOP()),
+            0x76 => Some(Instruction::HALT()),
+            0x40..=0x7F => Some(Instruction::LD(
+                ArithmeticTarget::from_bits(opcode >> 3),
+                LoadSource::Register(ArithmeticTarget::from_bits(opcode)),
+            )),
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => Some(Instruction::LD(
+                ArithmeticTarget::from_bits(opcode >> 3),
+                LoadSource::D8,
+            )),
+            0x01 => Some(Instruction::LD16(WordTarget::BC)),
+            0x11 => Some(Instruction::LD16(WordTarget::DE)),
+            0x21 => Some(Instruction::LD16(WordTarget::HL)),
+            0x31 => Some(Instruction::LD16(WordTarget::SP)),
+            0x2F => Some(Instruction::CPL()),
+            0x37 => Some(Instruction::SCF()),
+            0x3F => Some(Instruction::CCF()),
+            0x27 => Some(Instruction::DAA()),
+            0xFB => Some(Instruction::EI()),
+            0xF3 => Some(Instruction::DI()),
+            0xD9 => Some(Instruction::RETI()),
+            0xC5 => Some(Instruction::PUSH(StackTarget::BC)),
+            0xD5 => Some(Instruction::PUSH(StackTarget::DE)),
+            0xE5 => Some(Instruction::PUSH(StackTarget::HL)),
+            0xF5 => Some(Instruction::PUSH(StackTarget::AF)),
+            0xC1 => Some(Instruction::POP(StackTarget::BC)),
+            0xD1 => Some(Instruction::POP(StackTarget::DE)),
+            0xE1 => Some(Instruction::POP(StackTarget::HL)),
+            0xF1 => Some(Instruction::POP(StackTarget::AF)),
+            0xC3 => Some(Instruction::JP(JumpCondition::Always)),
+            0xC2 => Some(Instruction::JP(JumpCondition::NotZero)),
+            0xCA => Some(Instruction::JP(JumpCondition::Zero)),
+            0xD2 => Some(Instruction::JP(JumpCondition::NotCarry)),
+            0xDA => Some(Instruction::JP(JumpCondition::Carry)),
+            0x18 => Some(Instruction::JR(JumpCondition::Always)),
+            0x20 => Some(Instruction::JR(JumpCondition::NotZero)),
+            0x28 => Some(Instruction::JR(JumpCondition::Zero)),
+            0x30 => Some(Instruction::JR(JumpCondition::NotCarry)),
+            0x38 => Some(Instruction::JR(JumpCondition::Carry)),
+            _ => None,
+        }
+    }
+
+    // every byte after 0xCB is a valid instruction
+    fn from_prefixed_byte(opcode: u8) -> Instruction {
+        let target = ArithmeticTarget::from_bits(opcode);
+        let bit = (opcode >> 3) & 0b111;
+        match opcode {
+            0x00..=0x07 => Instruction::RLC(target),
+            0x08..=0x0F => Instruction::RRC(target),
+            0x10..=0x17 => Instruction::RL(target),
+            0x18..=0x1F => Instruction::RR(target),
+            0x20..=0x27 => Instruction::SLA(target),
+            0x28..=0x2F => Instruction::SRA(target),
+            0x30..=0x37 => Instruction::SWAP(target),
+            0x38..=0x3F => Instruction::SRL(target),
+            0x40..=0x7F => Instruction::BIT(bit, target),
+            0x80..=0xBF => Instruction::RES(bit, target),
+            0xC0..=0xFF => Instruction::SET(bit, target),
+        }
+    }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum ArithmeticTarget {
     A,
     B,
@@ -126,6 +293,32 @@ enum ArithmeticTarget {
     E,
     H,
     L,
+    HLI, // the byte in memory that HL points to
+}
+
+impl ArithmeticTarget {
+    // the low three bits of an opcode select the register: B, C, D, E, H, L, (HL), A
+    fn from_bits(bits: u8) -> ArithmeticTarget {
+        match bits & 0b111 {
+            0 => ArithmeticTarget::B,
+            1 => ArithmeticTarget::C,
+            2 => ArithmeticTarget::D,
+            3 => ArithmeticTarget::E,
+            4 => ArithmeticTarget::H,
+            5 => ArithmeticTarget::L,
+            6 => ArithmeticTarget::HLI,
+            _ => ArithmeticTarget::A,
+        }
+    }
+
+    // (HL) operands take extra cycles for the memory accesses
+    fn cycles(&self, register: u8, indirect: u8) -> u8 {
+        if *self == ArithmeticTarget::HLI {
+            indirect
+        } else {
+            register
+        }
+    }
 }
 
 enum ADDHLTarget {
@@ -134,17 +327,373 @@ enum ADDHLTarget {
     HL,
 }
 
+#[derive(Debug, Copy, Clone)]
+enum WordTarget {
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+enum LoadSource {
+    Register(ArithmeticTarget),
+    D8, // 8-bit immediate
+}
+
+enum StackTarget {
+    BC,
+    DE,
+    HL,
+    AF,
+}
+
+enum JumpCondition {
+    Always,
+    Zero,
+    NotZero,
+    Carry,
+    NotCarry,
+}
+
+impl std::fmt::Display for ArithmeticTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ArithmeticTarget::A => "A",
+            ArithmeticTarget::B => "B",
+            ArithmeticTarget::C => "C",
+            ArithmeticTarget::D => "D",
+            ArithmeticTarget::E => "E",
+            ArithmeticTarget::H => "H",
+            ArithmeticTarget::L => "L",
+            ArithmeticTarget::HLI => "(HL)",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::fmt::Display for ADDHLTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ADDHLTarget::BC => "BC",
+            ADDHLTarget::DE => "DE",
+            ADDHLTarget::HL => "HL",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::fmt::Display for WordTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            WordTarget::BC => "BC",
+            WordTarget::DE => "DE",
+            WordTarget::HL => "HL",
+            WordTarget::SP => "SP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::fmt::Display for StackTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            StackTarget::BC => "BC",
+            StackTarget::DE => "DE",
+            StackTarget::HL => "HL",
+            StackTarget::AF => "AF",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl JumpCondition {
+    // rendered in front of the operand, e.g. the "NZ," in "JR NZ,$12"
+    #[allow(dead_code)]
+    fn prefix(&self) -> &'static str {
+        match self {
+            JumpCondition::Always => "",
+            JumpCondition::Zero => "Z,",
+            JumpCondition::NotZero => "NZ,",
+            JumpCondition::Carry => "C,",
+            JumpCondition::NotCarry => "NC,",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Mmu {
+    memory: [u8; 0x10000],
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Mmu {
+            memory: [0; 0x10000],
+        }
+    }
+}
+
+impl Mmu {
+    fn read_byte(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+
+    // without a memory bank controller the whole ROM has to fit in 0x0000-0x7FFF
+    fn load_rom(&mut self, bytes: &[u8]) -> Result<CartridgeHeader, RomError> {
+        let header = CartridgeHeader::parse(bytes)?;
+        if bytes.len() > 0x8000 {
+            return Err(RomError::TooLarge(bytes.len()));
+        }
+        self.memory[..bytes.len()].copy_from_slice(bytes);
+        Ok(header)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct CartridgeHeader {
+    title: String,
+    cartridge_type: u8,
+    rom_size: u8, // the header codes, not sizes in bytes
+    ram_size: u8,
+}
+
+impl CartridgeHeader {
+    const TITLE_START: usize = 0x0134;
+    const TITLE_END: usize = 0x0143;
+    // on CGB cartridges the title stops before the manufacturer code and the CGB flag
+    const CGB_TITLE_END: usize = 0x013E;
+    const CGB_FLAG: usize = 0x0143;
+    const CARTRIDGE_TYPE: usize = 0x0147;
+    const ROM_SIZE: usize = 0x0148;
+    const RAM_SIZE: usize = 0x0149;
+    const HEADER_CHECKSUM: usize = 0x014D;
+    const HEADER_END: usize = 0x0150;
+
+    fn parse(rom: &[u8]) -> Result<CartridgeHeader, RomError> {
+        if rom.len() < CartridgeHeader::HEADER_END {
+            return Err(RomError::TooSmall(rom.len()));
+        }
+
+        let expected = rom[CartridgeHeader::HEADER_CHECKSUM];
+        let actual = CartridgeHeader::checksum(rom);
+        if expected != actual {
+            return Err(RomError::BadChecksum { expected, actual });
+        }
+
+        // the title is padded with zeros, and CGB cartridges reuse its last bytes
+        let title_end = if rom[CartridgeHeader::CGB_FLAG] & 0x80 != 0 {
+            CartridgeHeader::CGB_TITLE_END
+        } else {
+            CartridgeHeader::TITLE_END
+        };
+        let title = rom[CartridgeHeader::TITLE_START..=title_end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .filter(|byte| byte.is_ascii_graphic() || **byte == b' ')
+            .map(|&byte| byte as char)
+            .collect();
+
+        Ok(CartridgeHeader {
+            title,
+            cartridge_type: rom[CartridgeHeader::CARTRIDGE_TYPE],
+            rom_size: rom[CartridgeHeader::ROM_SIZE],
+            ram_size: rom[CartridgeHeader::RAM_SIZE],
+        })
+    }
+
+    // x = x - byte - 1 over 0x0134-0x014C, as the boot ROM checks it
+    fn checksum(rom: &[u8]) -> u8 {
+        rom[CartridgeHeader::TITLE_START..CartridgeHeader::HEADER_CHECKSUM]
+            .iter()
+            .fold(0u8, |x, &byte| x.wrapping_sub(byte).wrapping_sub(1))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum RomError {
+    TooSmall(usize),
+    TooLarge(usize),
+    BadChecksum { expected: u8, actual: u8 },
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RomError::TooSmall(length) => {
+                write!(f, "ROM is too small to hold a header: {} bytes", length)
+            }
+            RomError::TooLarge(length) => {
+                write!(
+                    f,
+                    "ROM doesn't fit in 32 KiB without an MBC: {} bytes",
+                    length
+                )
+            }
+            RomError::BadChecksum { expected, actual } => write!(
+                f,
+                "bad header checksum: header says 0x{:02X}, computed 0x{:02X}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+enum StateError {
+    Truncated { length: usize },
+    BadMagic,
+    UnsupportedVersion(u16),
+    WrongLength { expected: usize, actual: usize },
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StateError::Truncated { length } => {
+                write!(f, "save state is truncated: only {} bytes", length)
+            }
+            StateError::BadMagic => write!(f, "not a save state: bad magic header"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::WrongLength { expected, actual } => write!(
+                f,
+                "save state has the wrong length: expected {} bytes, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[derive(Debug, PartialEq)]
+enum StepError {
+    UnknownOpcode { opcode: u8, address: u16 },
+}
+
+impl std::fmt::Display for StepError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StepError::UnknownOpcode { opcode, address } => write!(
+                f,
+                "unknown instruction 0x{:02X} at 0x{:04X}",
+                opcode, address
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StepError {}
+
 #[derive(Debug, Default)]
 struct CPU {
     registers: Registers,
+    pc: u16,
+    sp: u16,
+    halted: bool,
+    ime: bool,         // interrupt master enable
+    ime_pending: bool, // set by EI, which only enables IME after the next instruction
+    cycles: u64,       // clock cycles (T-cycles) run by step() so far
+    mmu: Mmu,
 }
 
 impl CPU {
-    fn execute(&mut self, instruction: Instruction) {
+    // opcodes that don't decode (illegal ones and those not implemented yet) are returned as
+    // an error with pc left on them, so callers can detect the gaps
+    fn step(&mut self) -> Result<u8, StepError> {
+        let dispatch_cycles = self.handle_interrupts();
+        if dispatch_cycles > 0 {
+            self.cycles += dispatch_cycles as u64;
+            return Ok(dispatch_cycles);
+        }
+        if self.halted {
+            self.cycles += 4;
+            return Ok(4);
+        }
+
+        let enable_ime = self.ime_pending;
+        let address = self.pc;
+        let opcode = self.fetch_byte();
+        let instruction = if opcode == 0xCB {
+            Instruction::from_prefixed_byte(self.fetch_byte())
+        } else {
+            match Instruction::from_byte(opcode) {
+                Some(instruction) => instruction,
+                None => {
+                    self.pc = address;
+                    return Err(StepError::UnknownOpcode { opcode, address });
+                }
+            }
+        };
+        let cycles = self.execute(instruction);
+        // only takes effect once the instruction after EI has run, unless that was a DI
+        if enable_ime && self.ime_pending {
+            self.ime = true;
+            self.ime_pending = false;
+        }
+        self.cycles += cycles as u64;
+        Ok(cycles)
+    }
+
+    const INTERRUPT_ENABLE: u16 = 0xFFFF;
+    const INTERRUPT_FLAG: u16 = 0xFF0F;
+
+    // jumps to the handler of the highest priority interrupt that is both requested and
+    // enabled, and returns the cycles the dispatch took (0 when nothing was dispatched)
+    fn handle_interrupts(&mut self) -> u8 {
+        let requested = self.mmu.read_byte(CPU::INTERRUPT_FLAG);
+        let pending = self.mmu.read_byte(CPU::INTERRUPT_ENABLE) & requested & 0x1F;
+        if pending == 0 {
+            return 0;
+        }
+        // a pending interrupt ends HALT even when IME is off, it just isn't serviced then
+        self.halted = false;
+        if !self.ime {
+            return 0;
+        }
+
+        // bit 0 (VBlank) has the highest priority, bit 4 (joypad) the lowest
+        let bit = pending.trailing_zeros() as u16;
+        self.mmu
+            .write_byte(CPU::INTERRUPT_FLAG, requested & !(1 << bit));
+        // a pending EI must not turn IME back on inside the handler
+        self.ime = false;
+        self.ime_pending = false;
+        self.push(self.pc);
+        self.pc = 0x0040 + bit * 0x08;
+        20
+    }
+
+    // reads the byte at pc and moves pc past it, so operands are consumed as they're read
+    fn fetch_byte(&mut self) -> u8 {
+        let byte = self.mmu.read_byte(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        byte
+    }
+
+    // immediates are little endian: low byte first
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch_byte() as u16;
+        let high = self.fetch_byte() as u16;
+        (high << 8) | low
+    }
+
+    // returns the number of clock cycles (T-cycles) the instruction took; for 0xCB-prefixed
+    // instructions that includes fetching the prefix
+    fn execute(&mut self, instruction: Instruction) -> u8 {
         match instruction {
             Instruction::ADD(target) => {
                 let value = self.get_value_from_target(target);
                 self.registers.a = self.add(value);
+                target.cycles(4, 8)
             }
             Instruction::ADDHL(target) => {
                 let value = match target {
@@ -154,34 +703,42 @@ impl CPU {
                 };
                 let sum = self.addhl(value);
                 self.registers.set_hl(sum);
+                8
             }
             Instruction::ADC(target) => {
                 let value = self.get_value_from_target(target);
                 self.registers.a = self.adc(value);
+                target.cycles(4, 8)
             }
             Instruction::SUB(target) => {
                 let value = self.get_value_from_target(target);
                 self.registers.a = self.sub(value);
+                target.cycles(4, 8)
             }
             Instruction::SBC(target) => {
                 let value = self.get_value_from_target(target);
                 self.registers.a = self.sbc(value);
+                target.cycles(4, 8)
             }
             Instruction::AND(target) => {
                 let value = self.get_value_from_target(target);
                 self.registers.a &= value;
+                target.cycles(4, 8)
             }
             Instruction::OR(target) => {
                 let value = self.get_value_from_target(target);
                 self.registers.a |= value;
+                target.cycles(4, 8)
             }
             Instruction::XOR(target) => {
                 let value = self.get_value_from_target(target);
                 self.registers.a ^= value;
+                target.cycles(4, 8)
             }
             Instruction::CP(target) => {
                 let value = self.get_value_from_target(target);
                 self.sub(value);
+                target.cycles(4, 8)
             }
             Instruction::INC(target) => {
                 match target {
@@ -192,7 +749,13 @@ impl CPU {
                     ArithmeticTarget::E => self.registers.e = self.inc(self.registers.e),
                     ArithmeticTarget::H => self.registers.h = self.inc(self.registers.h),
                     ArithmeticTarget::L => self.registers.l = self.inc(self.registers.l),
+                    ArithmeticTarget::HLI => {
+                        let address = self.registers.get_hl();
+                        let value = self.inc(self.mmu.read_byte(address));
+                        self.mmu.write_byte(address, value);
+                    }
                 };
+                target.cycles(4, 12)
             }
             Instruction::DEC(target) => {
                 match target {
@@ -203,28 +766,168 @@ impl CPU {
                     ArithmeticTarget::E => self.registers.e = self.dec(self.registers.e),
                     ArithmeticTarget::H => self.registers.h = self.dec(self.registers.h),
                     ArithmeticTarget::L => self.registers.l = self.dec(self.registers.l),
+                    ArithmeticTarget::HLI => {
+                        let address = self.registers.get_hl();
+                        let value = self.dec(self.mmu.read_byte(address));
+                        self.mmu.write_byte(address, value);
+                    }
                 };
+                target.cycles(4, 12)
+            }
+            Instruction::INC16(target) => {
+                // unlike the 8-bit INC/DEC, no flags are affected
+                let value = self.get_word_from_target(target);
+                self.set_word_to_target(target, value.wrapping_add(1));
+                8
+            }
+            Instruction::DEC16(target) => {
+                let value = self.get_word_from_target(target);
+                self.set_word_to_target(target, value.wrapping_sub(1));
+                8
+            }
+            Instruction::NOP() => 4,
+            Instruction::HALT() => {
+                self.halted = true;
+                4
+            }
+            Instruction::CPL() => {
+                self.registers.a = !self.registers.a;
+                self.registers.f.subtract = true;
+                self.registers.f.half_carry = true;
+                4
             }
             Instruction::CCF() => {
+                self.registers.f.subtract = false;
+                self.registers.f.half_carry = false;
                 self.registers.f.carry = !self.registers.f.carry;
+                4
             }
             Instruction::SCF() => {
+                self.registers.f.subtract = false;
+                self.registers.f.half_carry = false;
                 self.registers.f.carry = true;
+                4
             }
+            // same as the CB rotates on A, except zero is always cleared
             Instruction::RRA() => {
-                let carry = self.registers.f.carry as u8;
-                self.registers.f.carry = self.registers.a & 0x01 == 0x01;
-                self.registers.a >>= 1;
-                self.registers.a |= carry << 7;
+                self.registers.a = self.rr(self.registers.a);
+                self.registers.f.zero = false;
+                4
             }
             Instruction::RLA() => {
-                let carry = self.registers.f.carry as u8;
-                self.registers.f.carry = self.registers.a & 0x80 == 0x80;
-                self.registers.a <<= 1;
-                self.registers.a |= carry;
+                self.registers.a = self.rl(self.registers.a);
+                self.registers.f.zero = false;
+                4
             }
             Instruction::RRCA() => {
-                self.registers.a = self.registers.a.rotate_right(1);
+                self.registers.a = self.rrc(self.registers.a);
+                self.registers.f.zero = false;
+                4
+            }
+            Instruction::RLCA() => {
+                self.registers.a = self.rlc(self.registers.a);
+                self.registers.f.zero = false;
+                4
+            }
+            Instruction::DAA() => {
+                self.daa();
+                4
+            }
+            Instruction::BIT(bit, target) => {
+                let value = self.get_value_from_target(target);
+                self.registers.f.zero = value & (1 << bit) == 0;
+                self.registers.f.subtract = false;
+                self.registers.f.half_carry = true;
+                target.cycles(8, 12) // (HL) is only read, not written back
+            }
+            Instruction::SET(bit, target) => {
+                let value = self.get_value_from_target(target);
+                self.set_value_to_target(target, value | (1 << bit));
+                target.cycles(8, 16)
+            }
+            Instruction::RES(bit, target) => {
+                let value = self.get_value_from_target(target);
+                self.set_value_to_target(target, value & !(1 << bit));
+                target.cycles(8, 16)
+            }
+            Instruction::RLC(target) => self.modify_target(target, CPU::rlc),
+            Instruction::RRC(target) => self.modify_target(target, CPU::rrc),
+            Instruction::RL(target) => self.modify_target(target, CPU::rl),
+            Instruction::RR(target) => self.modify_target(target, CPU::rr),
+            Instruction::SLA(target) => self.modify_target(target, CPU::sla),
+            Instruction::SRA(target) => self.modify_target(target, CPU::sra),
+            Instruction::SWAP(target) => self.modify_target(target, CPU::swap),
+            Instruction::SRL(target) => self.modify_target(target, CPU::srl),
+            Instruction::PUSH(target) => {
+                let value = match target {
+                    StackTarget::BC => self.registers.get_bc(),
+                    StackTarget::DE => self.registers.get_de(),
+                    StackTarget::HL => self.registers.get_hl(),
+                    StackTarget::AF => self.registers.get_af(),
+                };
+                self.push(value);
+                16
+            }
+            Instruction::POP(target) => {
+                let value = self.pop();
+                match target {
+                    StackTarget::BC => self.registers.set_bc(value),
+                    StackTarget::DE => self.registers.set_de(value),
+                    StackTarget::HL => self.registers.set_hl(value),
+                    StackTarget::AF => self.registers.set_af(value), // drops F's low nibble
+                }
+                12
+            }
+            Instruction::JP(condition) => {
+                let address = self.fetch_word();
+                if self.should_jump(condition) {
+                    self.pc = address;
+                    16
+                } else {
+                    12
+                }
+            }
+            Instruction::JR(condition) => {
+                // the offset is relative to the address after the operand
+                let offset = self.fetch_byte() as i8;
+                if self.should_jump(condition) {
+                    self.pc = self.pc.wrapping_add_signed(offset as i16);
+                    12
+                } else {
+                    8
+                }
+            }
+            Instruction::EI() => {
+                self.ime_pending = true;
+                4
+            }
+            Instruction::DI() => {
+                self.ime = false;
+                self.ime_pending = false;
+                4
+            }
+            Instruction::RETI() => {
+                self.pc = self.pop();
+                self.ime = true; // unlike EI, immediately
+                16
+            }
+            Instruction::LD(target, source) => match source {
+                LoadSource::Register(source) => {
+                    let value = self.get_value_from_target(source);
+                    self.set_value_to_target(target, value);
+                    // at most one side is (HL), 0x76 being HALT
+                    target.cycles(source.cycles(4, 8), 8)
+                }
+                LoadSource::D8 => {
+                    let value = self.fetch_byte();
+                    self.set_value_to_target(target, value);
+                    target.cycles(8, 12)
+                }
+            },
+            Instruction::LD16(target) => {
+                let value = self.fetch_word();
+                self.set_word_to_target(target, value);
+                12
             }
         }
     }
@@ -238,14 +941,78 @@ impl CPU {
             ArithmeticTarget::E => self.registers.e,
             ArithmeticTarget::H => self.registers.h,
             ArithmeticTarget::L => self.registers.l,
+            ArithmeticTarget::HLI => self.mmu.read_byte(self.registers.get_hl()),
+        }
+    }
+
+    fn set_value_to_target(&mut self, target: ArithmeticTarget, value: u8) {
+        match target {
+            ArithmeticTarget::A => self.registers.a = value,
+            ArithmeticTarget::B => self.registers.b = value,
+            ArithmeticTarget::C => self.registers.c = value,
+            ArithmeticTarget::D => self.registers.d = value,
+            ArithmeticTarget::E => self.registers.e = value,
+            ArithmeticTarget::H => self.registers.h = value,
+            ArithmeticTarget::L => self.registers.l = value,
+            ArithmeticTarget::HLI => self.mmu.write_byte(self.registers.get_hl(), value),
+        }
+    }
+
+    fn should_jump(&self, condition: JumpCondition) -> bool {
+        match condition {
+            JumpCondition::Always => true,
+            JumpCondition::Zero => self.registers.f.zero,
+            JumpCondition::NotZero => !self.registers.f.zero,
+            JumpCondition::Carry => self.registers.f.carry,
+            JumpCondition::NotCarry => !self.registers.f.carry,
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.mmu.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.mmu.write_byte(self.sp, (value & 0xFF) as u8);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let low = self.mmu.read_byte(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.mmu.read_byte(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        (high << 8) | low
+    }
+
+    fn get_word_from_target(&self, target: WordTarget) -> u16 {
+        match target {
+            WordTarget::BC => self.registers.get_bc(),
+            WordTarget::DE => self.registers.get_de(),
+            WordTarget::HL => self.registers.get_hl(),
+            WordTarget::SP => self.sp,
+        }
+    }
+
+    fn set_word_to_target(&mut self, target: WordTarget, value: u16) {
+        match target {
+            WordTarget::BC => self.registers.set_bc(value),
+            WordTarget::DE => self.registers.set_de(value),
+            WordTarget::HL => self.registers.set_hl(value),
+            WordTarget::SP => self.sp = value,
         }
     }
 
+    fn modify_target(&mut self, target: ArithmeticTarget, operation: fn(&mut CPU, u8) -> u8) -> u8 {
+        let value = self.get_value_from_target(target);
+        let result = operation(self, value);
+        self.set_value_to_target(target, result);
+        target.cycles(8, 16)
+    }
+
     fn add(&mut self, value: u8) -> u8 {
         let (result, carry) = self.registers.a.overflowing_add(value);
         self.registers.f.zero = result == 0;
         self.registers.f.subtract = false;
-        self.registers.f.half_carry = ((self.registers.a & 0xF) + (result & 0xF) & 0x10) == 0x10;
+        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
         self.registers.f.carry = carry;
 
         result
@@ -253,10 +1020,9 @@ impl CPU {
 
     fn addhl(&mut self, value: u16) -> u16 {
         let (result, carry) = self.registers.get_hl().overflowing_add(value);
-        self.registers.f.zero = result == 0;
+        // zero is left untouched by the 16-bit add
         self.registers.f.subtract = false;
-        self.registers.f.half_carry =
-            ((self.registers.get_hl() & 0xFFF) + (result & 0xFFF)) > 0xFFF;
+        self.registers.f.half_carry = (self.registers.get_hl() & 0xFFF) + (value & 0xFFF) > 0xFFF;
         self.registers.f.carry = carry;
 
         result
@@ -264,12 +1030,13 @@ impl CPU {
 
     fn adc(&mut self, value: u8) -> u8 {
         let previous_carry = self.registers.f.carry as u8;
-        let (mut result, carry) = self.registers.a.overflowing_add(value);
-        result += carry as u8 + previous_carry;
-        self.registers.f.carry = carry;
+        let (result, carry) = self.registers.a.overflowing_add(value);
+        let (result, previous_carry_overflow) = result.overflowing_add(previous_carry);
         self.registers.f.zero = result == 0;
         self.registers.f.subtract = false;
-        self.registers.f.half_carry = ((self.registers.a & 0xF) + (result & 0xF) & 0x10) == 0x10;
+        self.registers.f.half_carry =
+            (self.registers.a & 0xF) + (value & 0xF) + previous_carry > 0xF;
+        self.registers.f.carry = carry || previous_carry_overflow;
 
         result
     }
@@ -278,48 +1045,312 @@ impl CPU {
         let (result, borrow) = self.registers.a.overflowing_sub(value);
         self.registers.f.zero = result == 0;
         self.registers.f.subtract = true;
-        self.registers.f.half_carry = (self.registers.a & 0xF) < (result & 0xF);
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF);
         self.registers.f.carry = borrow;
 
         result
     }
 
     fn sbc(&mut self, value: u8) -> u8 {
-        let (mut result, borrow) = self.registers.a.overflowing_sub(value);
-        result -= borrow as u8;
+        let previous_carry = self.registers.f.carry as u8;
+        let (result, borrow) = self.registers.a.overflowing_sub(value);
+        let (result, carry_borrow) = result.overflowing_sub(previous_carry);
         self.registers.f.zero = result == 0;
         self.registers.f.subtract = true;
-        self.registers.f.half_carry = (self.registers.a & 0xF) < (result & 0xF);
-        self.registers.f.carry = borrow;
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF) + previous_carry;
+        self.registers.f.carry = borrow || carry_borrow;
 
         result
     }
 
     fn inc(&mut self, target: u8) -> u8 {
-        let (result, carry) = target.overflowing_add(1);
+        let result = target.wrapping_add(1); // INC leaves carry untouched
         self.registers.f.zero = result == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = (target & 0xF) == 0xF; // if the lower nibble is 0xF, then adding 1
                                                              // will carry to the higher nibble
-        self.registers.f.carry = carry;
 
         result
     }
 
     fn dec(&mut self, target: u8) -> u8 {
-        let (result, carry) = target.overflowing_sub(1);
+        let result = target.wrapping_sub(1); // DEC leaves carry untouched
         self.registers.f.zero = result == 0;
         self.registers.f.subtract = true;
         self.registers.f.half_carry = (target & 0xF) == 0x0; // if the lower nibble is 0x0, then subtracting 1
                                                              // will borrow from the higher nibble
+
+        result
+    }
+
+    // adjusts A back into packed BCD after an ADD/ADC or SUB/SBC of two BCD values
+    fn daa(&mut self) {
+        let mut correction = 0;
+        let mut carry = self.registers.f.carry;
+        if self.registers.f.half_carry
+            || (!self.registers.f.subtract && (self.registers.a & 0xF) > 0x9)
+        {
+            correction |= 0x06;
+        }
+        if carry || (!self.registers.f.subtract && self.registers.a > 0x99) {
+            correction |= 0x60;
+            carry = true;
+        }
+
+        self.registers.a = if self.registers.f.subtract {
+            self.registers.a.wrapping_sub(correction)
+        } else {
+            self.registers.a.wrapping_add(correction)
+        };
+        self.registers.f.zero = self.registers.a == 0;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = carry;
+    }
+
+    // flags shared by the CB rotates, shifts and SWAP
+    fn set_shift_flags(&mut self, result: u8, carry: bool) {
+        self.registers.f.zero = result == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = false;
         self.registers.f.carry = carry;
+    }
+
+    fn rlc(&mut self, value: u8) -> u8 {
+        let result = value.rotate_left(1);
+        self.set_shift_flags(result, value & 0x80 == 0x80);
+        result
+    }
+
+    fn rrc(&mut self, value: u8) -> u8 {
+        let result = value.rotate_right(1);
+        self.set_shift_flags(result, value & 0x01 == 0x01);
+        result
+    }
+
+    fn rl(&mut self, value: u8) -> u8 {
+        let result = (value << 1) | self.registers.f.carry as u8;
+        self.set_shift_flags(result, value & 0x80 == 0x80);
+        result
+    }
+
+    fn rr(&mut self, value: u8) -> u8 {
+        let result = (value >> 1) | (self.registers.f.carry as u8) << 7;
+        self.set_shift_flags(result, value & 0x01 == 0x01);
+        result
+    }
+
+    fn sla(&mut self, value: u8) -> u8 {
+        let result = value << 1;
+        self.set_shift_flags(result, value & 0x80 == 0x80);
+        result
+    }
 
+    fn sra(&mut self, value: u8) -> u8 {
+        let result = (value >> 1) | (value & 0x80); // bit 7 keeps its value
+        self.set_shift_flags(result, value & 0x01 == 0x01);
         result
     }
+
+    fn swap(&mut self, value: u8) -> u8 {
+        let result = value.rotate_left(4);
+        self.set_shift_flags(result, false);
+        result
+    }
+
+    fn srl(&mut self, value: u8) -> u8 {
+        let result = value >> 1;
+        self.set_shift_flags(result, value & 0x01 == 0x01);
+        result
+    }
+
+    #[allow(dead_code)]
+    const STATE_MAGIC: [u8; 4] = *b"GBST";
+    // version 2 added ime and ime_pending, version 3 added cycles
+    #[allow(dead_code)]
+    const STATE_VERSION: u16 = 3;
+    // magic, version
+    #[allow(dead_code)]
+    const STATE_HEADER_LENGTH: usize = 4 + 2;
+    // header, A B C D E F H L, pc, sp, halted, ime, ime_pending, cycles, memory
+    #[allow(dead_code)]
+    const STATE_LENGTH: usize = CPU::STATE_HEADER_LENGTH + 8 + 2 + 2 + 3 + 8 + 0x10000;
+
+    // multi-byte fields are little endian
+    #[allow(dead_code)]
+    fn save_state(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(CPU::STATE_LENGTH);
+        data.extend_from_slice(&CPU::STATE_MAGIC);
+        data.extend_from_slice(&CPU::STATE_VERSION.to_le_bytes());
+        data.extend_from_slice(&[
+            self.registers.a,
+            self.registers.b,
+            self.registers.c,
+            self.registers.d,
+            self.registers.e,
+            u8::from(self.registers.f),
+            self.registers.h,
+            self.registers.l,
+        ]);
+        data.extend_from_slice(&self.pc.to_le_bytes());
+        data.extend_from_slice(&self.sp.to_le_bytes());
+        data.push(self.halted as u8);
+        data.push(self.ime as u8);
+        data.push(self.ime_pending as u8);
+        data.extend_from_slice(&self.cycles.to_le_bytes());
+        data.extend_from_slice(&self.mmu.memory);
+        data
+    }
+
+    // the CPU is left untouched unless the whole buffer is valid
+    #[allow(dead_code)]
+    fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        if data.len() < CPU::STATE_HEADER_LENGTH {
+            return Err(StateError::Truncated { length: data.len() });
+        }
+        if data[0..4] != CPU::STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != CPU::STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if data.len() < CPU::STATE_LENGTH {
+            return Err(StateError::Truncated { length: data.len() });
+        }
+        if data.len() > CPU::STATE_LENGTH {
+            return Err(StateError::WrongLength {
+                expected: CPU::STATE_LENGTH,
+                actual: data.len(),
+            });
+        }
+
+        let registers = &data[CPU::STATE_HEADER_LENGTH..];
+        self.registers.a = registers[0];
+        self.registers.b = registers[1];
+        self.registers.c = registers[2];
+        self.registers.d = registers[3];
+        self.registers.e = registers[4];
+        self.registers.f = FlagsRegister::from(registers[5]);
+        self.registers.h = registers[6];
+        self.registers.l = registers[7];
+        self.pc = u16::from_le_bytes([registers[8], registers[9]]);
+        self.sp = u16::from_le_bytes([registers[10], registers[11]]);
+        self.halted = registers[12] != 0;
+        self.ime = registers[13] != 0;
+        self.ime_pending = registers[14] != 0;
+        self.cycles = u64::from_le_bytes(registers[15..23].try_into().unwrap());
+        self.mmu.memory.copy_from_slice(&registers[23..]);
+        Ok(())
+    }
 }
 
+// returns the mnemonic at address and the number of bytes it occupies, decoding through the same
+// tables as CPU::step; bytes that aren't an instruction come back as "DB $xx" with length 1
+#[allow(dead_code)]
+fn disassemble(mmu: &Mmu, address: u16) -> (String, u16) {
+    let byte_at = |offset: u16| mmu.read_byte(address.wrapping_add(offset));
+    let opcode = byte_at(0);
+    let (instruction, opcode_length) = if opcode == 0xCB {
+        (Instruction::from_prefixed_byte(byte_at(1)), 2)
+    } else {
+        match Instruction::from_byte(opcode) {
+            Some(instruction) => (instruction, 1),
+            None => return (format!("DB ${:02X}", opcode), 1),
+        }
+    };
+    let immediate8 = byte_at(opcode_length);
+    let immediate16 = (byte_at(opcode_length + 1) as u16) << 8 | immediate8 as u16;
+
+    let (text, operand_length) = match instruction {
+        Instruction::ADD(target) => (format!("ADD A,{}", target), 0),
+        Instruction::ADDHL(target) => (format!("ADD HL,{}", target), 0),
+        Instruction::ADC(target) => (format!("ADC A,{}", target), 0),
+        Instruction::SUB(target) => (format!("SUB {}", target), 0),
+        Instruction::SBC(target) => (format!("SBC A,{}", target), 0),
+        Instruction::AND(target) => (format!("AND {}", target), 0),
+        Instruction::OR(target) => (format!("OR {}", target), 0),
+        Instruction::XOR(target) => (format!("XOR {}", target), 0),
+        Instruction::CP(target) => (format!("CP {}", target), 0),
+        Instruction::INC(target) => (format!("INC {}", target), 0),
+        Instruction::DEC(target) => (format!("DEC {}", target), 0),
+        Instruction::INC16(target) => (format!("INC {}", target), 0),
+        Instruction::DEC16(target) => (format!("DEC {}", target), 0),
+        Instruction::NOP() => ("NOP".to_string(), 0),
+        Instruction::HALT() => ("HALT".to_string(), 0),
+        Instruction::CPL() => ("CPL".to_string(), 0),
+        Instruction::CCF() => ("CCF".to_string(), 0),
+        Instruction::SCF() => ("SCF".to_string(), 0),
+        Instruction::RRA() => ("RRA".to_string(), 0),
+        Instruction::RLA() => ("RLA".to_string(), 0),
+        Instruction::RRCA() => ("RRCA".to_string(), 0),
+        Instruction::RLCA() => ("RLCA".to_string(), 0),
+        Instruction::DAA() => ("DAA".to_string(), 0),
+        Instruction::EI() => ("EI".to_string(), 0),
+        Instruction::DI() => ("DI".to_string(), 0),
+        Instruction::RETI() => ("RETI".to_string(), 0),
+        Instruction::BIT(bit, target) => (format!("BIT {},{}", bit, target), 0),
+        Instruction::SET(bit, target) => (format!("SET {},{}", bit, target), 0),
+        Instruction::RES(bit, target) => (format!("RES {},{}", bit, target), 0),
+        Instruction::RLC(target) => (format!("RLC {}", target), 0),
+        Instruction::RRC(target) => (format!("RRC {}", target), 0),
+        Instruction::RL(target) => (format!("RL {}", target), 0),
+        Instruction::RR(target) => (format!("RR {}", target), 0),
+        Instruction::SLA(target) => (format!("SLA {}", target), 0),
+        Instruction::SRA(target) => (format!("SRA {}", target), 0),
+        Instruction::SWAP(target) => (format!("SWAP {}", target), 0),
+        Instruction::SRL(target) => (format!("SRL {}", target), 0),
+        Instruction::PUSH(target) => (format!("PUSH {}", target), 0),
+        Instruction::POP(target) => (format!("POP {}", target), 0),
+        Instruction::JP(condition) => (format!("JP {}${:04X}", condition.prefix(), immediate16), 2),
+        Instruction::JR(condition) => (format!("JR {}${:02X}", condition.prefix(), immediate8), 1),
+        Instruction::LD(target, source) => match source {
+            LoadSource::Register(source) => (format!("LD {},{}", target, source), 0),
+            LoadSource::D8 => (format!("LD {},${:02X}", target, immediate8), 1),
+        },
+        Instruction::LD16(target) => (format!("LD {},${:04X}", target, immediate16), 2),
+    };
+
+    (text, opcode_length + operand_length)
+}
+
+// runs a ROM from 0x0100, as the boot ROM leaves it, until it halts or reaches an opcode step()
+// doesn't decode yet
 fn main() {
-    println!("Hello, world!");
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: gbemulator <rom>");
+            std::process::exit(1);
+        }
+    };
+    let rom = match std::fs::read(&path) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{}: {}", path, error);
+            std::process::exit(1);
+        }
+    };
+
+    let mut cpu = CPU {
+        pc: 0x0100,
+        sp: 0xFFFE,
+        ..Default::default()
+    };
+    match cpu.mmu.load_rom(&rom) {
+        Ok(header) => println!("{}", header.title),
+        Err(error) => {
+            eprintln!("{}: {}", path, error);
+            std::process::exit(1);
+        }
+    }
+
+    while !cpu.halted {
+        if let Err(error) = cpu.step() {
+            eprintln!("{} after {} cycles", error, cpu.cycles);
+            std::process::exit(1);
+        }
+    }
+    println!("halted at 0x{:04X} after {} cycles", cpu.pc, cpu.cycles);
 }
 
 #[cfg(test)]
@@ -336,6 +1367,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_registers() {
         let mut registers: Registers = {
             Registers {
@@ -364,13 +1396,63 @@ mod tests {
     }
 
     #[test]
-    fn test_add() {
-        let mut cpu = CPU::default();
+    fn test_af_low_nibble() {
+        let mut registers = Registers::default();
+        registers.set_af(0xFFFF);
+        assert_eq!(0xFFF0, registers.get_af()); // F has no bits 3-0
+        assert_eq!(0xFF, registers.a);
+        assert_eq!(0xF0, u8::from(registers.f));
+    }
 
-        cpu.registers.a = 0x00;
-        cpu.registers.c = 0x01;
-        cpu.execute(Instruction::ADD(ArithmeticTarget::C));
-        assert_eq!(0x01, cpu.registers.a);
+    #[test]
+    fn test_af_mask_independent_of_f_type() {
+        let mut registers = Registers::default();
+        registers.set_af(0xBEEF);
+        assert_eq!(0xBEE0, registers.get_af());
+
+        // the pair masks F itself, so a raw u8 low register would be masked too
+        struct RawAf {
+            a: u8,
+            f: u8,
+        }
+        impl RawAf {
+            register_pair!(a, f, 0xF0);
+        }
+        let mut raw = RawAf { a: 0, f: 0 };
+        raw.set_af(0xBEEF);
+        assert_eq!(0xE0, raw.f);
+        assert_eq!(0xBEE0, raw.get_af());
+        raw.f = 0xFF;
+        assert_eq!(0xBEF0, raw.get_af());
+    }
+
+    #[test]
+    fn test_flag_bitor() {
+        assert_eq!(FlagsRegister::from(0xA0), Flag::Zero | Flag::HalfCarry);
+        assert_eq!(
+            FlagsRegister::from(0xD0),
+            Flag::Zero | Flag::Subtract | Flag::Carry
+        );
+        assert_eq!(
+            FlagsRegister::from(0x40),
+            FlagsRegister::from(Flag::Subtract)
+        );
+
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0x01;
+        cpu.registers.c = 0x01;
+        cpu.execute(Instruction::SUB(ArithmeticTarget::C));
+        assert_eq!(Flag::Zero | Flag::Subtract, cpu.registers.f);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut cpu = CPU::default();
+
+        cpu.registers.a = 0x00;
+        cpu.registers.c = 0x01;
+        cpu.execute(Instruction::ADD(ArithmeticTarget::C));
+        assert_eq!(0x01, cpu.registers.a);
         assert_eq!(0, u8::from(cpu.registers.f));
 
         cpu.registers.a = 0xFF;
@@ -380,13 +1462,1001 @@ mod tests {
         assert!(cpu.registers.f.carry);
     }
 
+    #[test]
+    fn test_mmu() {
+        let mut mmu = Mmu::default();
+        assert_eq!(0x00, mmu.read_byte(0xC000));
+        mmu.write_byte(0xC000, 0x42);
+        mmu.write_byte(0xFFFF, 0x24);
+        assert_eq!(0x42, mmu.read_byte(0xC000));
+        assert_eq!(0x24, mmu.read_byte(0xFFFF));
+        assert_eq!(0x00, mmu.read_byte(0xC001));
+    }
+
+    fn synthetic_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x013B].copy_from_slice(b"TESTROM");
+        rom[0x0147] = 0x00; // ROM only
+        rom[0x0148] = 0x00; // 32 KiB
+        rom[0x0149] = 0x00; // no RAM
+        rom[0x014D] = CartridgeHeader::checksum(&rom);
+        rom[0x0150] = 0x3C;
+        rom
+    }
+
+    #[test]
+    fn test_load_rom() {
+        let rom = synthetic_rom();
+        // -(0x19 header bytes) - ('T' + 'E' + 'S' + 'T' + 'R' + 'O' + 'M') = -583
+        assert_eq!(0xB9, rom[0x014D]);
+
+        let mut mmu = Mmu::default();
+        let header = mmu.load_rom(&rom).unwrap();
+        assert_eq!(
+            CartridgeHeader {
+                title: "TESTROM".to_string(),
+                cartridge_type: 0x00,
+                rom_size: 0x00,
+                ram_size: 0x00,
+            },
+            header
+        );
+        assert_eq!(0x3C, mmu.read_byte(0x0150));
+        assert_eq!(b'T', mmu.read_byte(0x0134));
+    }
+
+    #[test]
+    fn test_cgb_title() {
+        let mut rom = synthetic_rom();
+        rom[0x0134..0x0144].copy_from_slice(b"POKEMON_SLVAAXE\x80");
+        rom[0x014D] = CartridgeHeader::checksum(&rom);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!("POKEMON_SLV", header.title); // without the manufacturer code and flag
+
+        // DMG titles may use all 16 bytes, but non-printable ones are dropped
+        rom[0x0134..0x0144].copy_from_slice(b"SIXTEEN\x01CHARS!!\x7F");
+        rom[0x014D] = CartridgeHeader::checksum(&rom);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!("SIXTEENCHARS!!", header.title);
+    }
+
+    #[test]
+    fn test_load_rom_errors() {
+        let mut rom = synthetic_rom();
+        rom[0x014D] ^= 0xFF;
+        let mut mmu = Mmu::default();
+        assert_eq!(
+            Err(RomError::BadChecksum {
+                expected: 0x46,
+                actual: 0xB9
+            }),
+            mmu.load_rom(&rom)
+        );
+        assert_eq!(0x00, mmu.read_byte(0x0150)); // nothing was copied
+
+        assert_eq!(Err(RomError::TooSmall(100)), mmu.load_rom(&[0; 100]));
+
+        let mut rom = synthetic_rom();
+        rom.resize(0x10000, 0);
+        assert_eq!(Err(RomError::TooLarge(0x10000)), mmu.load_rom(&rom));
+    }
+
+    #[test]
+    fn test_hl_indirect() {
+        let mut cpu = CPU::default();
+        cpu.registers.set_hl(0xC000);
+        cpu.mmu.write_byte(0xC000, 0x0F);
+
+        cpu.registers.a = 0x01;
+        cpu.execute(Instruction::ADD(ArithmeticTarget::HLI));
+        assert_eq!(0x10, cpu.registers.a);
+        assert_eq!(0x0F, cpu.mmu.read_byte(0xC000)); // the operand is only read
+        test_flags!(cpu, false, false, true, false);
+
+        cpu.execute(Instruction::SUB(ArithmeticTarget::HLI));
+        assert_eq!(0x01, cpu.registers.a);
+
+        cpu.execute(Instruction::INC(ArithmeticTarget::HLI));
+        assert_eq!(0x10, cpu.mmu.read_byte(0xC000));
+        assert_eq!(0xC000, cpu.registers.get_hl()); // HL itself is untouched
+        test_flags!(cpu, false, false, true, false);
+
+        cpu.mmu.write_byte(0xC000, 0x01);
+        cpu.execute(Instruction::DEC(ArithmeticTarget::HLI));
+        assert_eq!(0x00, cpu.mmu.read_byte(0xC000));
+        test_flags!(cpu, true, true, false, false);
+
+        cpu.registers.set_hl(0xC001);
+        cpu.execute(Instruction::DEC(ArithmeticTarget::HLI));
+        assert_eq!(0xFF, cpu.mmu.read_byte(0xC001));
+        assert_eq!(0x00, cpu.mmu.read_byte(0xC000));
+    }
+
+    #[test]
+    fn test_from_byte() {
+        assert!(matches!(
+            Instruction::from_byte(0x80),
+            Some(Instruction::ADD(ArithmeticTarget::B))
+        ));
+        assert!(matches!(
+            Instruction::from_byte(0xBE),
+            Some(Instruction::CP(ArithmeticTarget::HLI))
+        ));
+        assert!(matches!(
+            Instruction::from_byte(0x3C),
+            Some(Instruction::INC(ArithmeticTarget::A))
+        ));
+        assert!(matches!(
+            Instruction::from_byte(0x35),
+            Some(Instruction::DEC(ArithmeticTarget::HLI))
+        ));
+        assert!((0x80..=0xBF).all(|opcode| Instruction::from_byte(opcode).is_some()));
+        assert!(Instruction::from_byte(0xD3).is_none()); // not an opcode on any Game Boy
+    }
+
+    #[test]
+    fn test_step() {
+        let mut cpu = CPU::default();
+        cpu.registers.set_hl(0xC000);
+        let program = [
+            0x3C, // INC A
+            0x3C, // INC A
+            0x04, // INC B
+            0x80, // ADD A,B
+            0x90, // SUB B
+            0x34, // INC (HL)
+            0x86, // ADD A,(HL)
+        ];
+        load_program(&mut cpu, 0x0000, &program);
+
+        cpu.step().unwrap();
+        assert_eq!(0x01, cpu.registers.a);
+        assert_eq!(1, cpu.pc);
+
+        cpu.step().unwrap();
+        assert_eq!(0x02, cpu.registers.a);
+        assert_eq!(2, cpu.pc);
+
+        cpu.step().unwrap();
+        assert_eq!(0x01, cpu.registers.b);
+        assert_eq!(3, cpu.pc);
+
+        cpu.step().unwrap();
+        assert_eq!(0x03, cpu.registers.a);
+
+        cpu.step().unwrap();
+        assert_eq!(0x02, cpu.registers.a);
+
+        cpu.step().unwrap();
+        assert_eq!(0x01, cpu.mmu.read_byte(0xC000));
+
+        cpu.step().unwrap();
+        assert_eq!(0x03, cpu.registers.a);
+        assert_eq!(7, cpu.pc);
+    }
+
+    #[test]
+    fn test_step_unknown_opcode() {
+        let mut cpu = CPU::default();
+        load_program(&mut cpu, 0x0100, &[0x00, 0xD3]); // NOP; illegal
+        cpu.pc = 0x0100;
+        assert_eq!(Ok(4), cpu.step());
+        assert_eq!(
+            Err(StepError::UnknownOpcode {
+                opcode: 0xD3,
+                address: 0x0101
+            }),
+            cpu.step()
+        );
+        assert_eq!(0x0101, cpu.pc);
+        assert_eq!(4, cpu.cycles);
+        assert_eq!(
+            "unknown instruction 0xD3 at 0x0101",
+            StepError::UnknownOpcode {
+                opcode: 0xD3,
+                address: 0x0101
+            }
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_daa() {
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0x09;
+        cpu.registers.b = 0x01;
+        cpu.execute(Instruction::ADD(ArithmeticTarget::B));
+        assert_eq!(0x0A, cpu.registers.a);
+        cpu.execute(Instruction::DAA());
+        assert_eq!(0x10, cpu.registers.a); // 09 + 01 = 10
+        test_flags!(cpu, false, false, false, false);
+
+        cpu.registers.a = 0x45;
+        cpu.registers.b = 0x38;
+        cpu.execute(Instruction::ADD(ArithmeticTarget::B));
+        cpu.execute(Instruction::DAA());
+        assert_eq!(0x83, cpu.registers.a); // 45 + 38 = 83
+        test_flags!(cpu, false, false, false, false);
+
+        cpu.registers.a = 0x99;
+        cpu.registers.b = 0x01;
+        cpu.execute(Instruction::ADD(ArithmeticTarget::B));
+        cpu.execute(Instruction::DAA());
+        assert_eq!(0x00, cpu.registers.a); // 99 + 01 = 100
+        test_flags!(cpu, true, false, false, true);
+
+        cpu.registers.a = 0x10;
+        cpu.registers.b = 0x01;
+        cpu.execute(Instruction::SUB(ArithmeticTarget::B));
+        assert_eq!(0x0F, cpu.registers.a);
+        cpu.execute(Instruction::DAA());
+        assert_eq!(0x09, cpu.registers.a); // 10 - 01 = 09
+        test_flags!(cpu, false, true, false, false);
+
+        cpu.registers.a = 0x20;
+        cpu.registers.b = 0x35;
+        cpu.execute(Instruction::SUB(ArithmeticTarget::B));
+        cpu.execute(Instruction::DAA());
+        assert_eq!(0x85, cpu.registers.a); // 20 - 35 = -15, i.e. 85 with a borrow
+        test_flags!(cpu, false, true, false, true);
+    }
+
+    #[test]
+    fn test_bit() {
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0x80;
+        cpu.execute(Instruction::BIT(7, ArithmeticTarget::A));
+        test_flags!(cpu, false, false, true, false);
+
+        cpu.registers.a = 0x00;
+        cpu.registers.f.carry = true;
+        cpu.execute(Instruction::BIT(7, ArithmeticTarget::A));
+        test_flags!(cpu, true, false, true, true); // carry is untouched
+        assert_eq!(0x00, cpu.registers.a);
+
+        cpu.registers.set_hl(0xC000);
+        cpu.mmu.write_byte(0xC000, 0b0000_0100);
+        cpu.execute(Instruction::BIT(2, ArithmeticTarget::HLI));
+        assert!(!cpu.registers.f.zero);
+        cpu.execute(Instruction::BIT(3, ArithmeticTarget::HLI));
+        assert!(cpu.registers.f.zero);
+    }
+
+    #[test]
+    fn test_set_res() {
+        let mut cpu = CPU::default();
+        cpu.registers.f = Flag::Zero | Flag::Carry;
+        cpu.execute(Instruction::SET(3, ArithmeticTarget::B));
+        assert_eq!(0b0000_1000, cpu.registers.b);
+        cpu.execute(Instruction::SET(7, ArithmeticTarget::B));
+        assert_eq!(0b1000_1000, cpu.registers.b);
+        cpu.execute(Instruction::RES(3, ArithmeticTarget::B));
+        assert_eq!(0b1000_0000, cpu.registers.b);
+        assert_eq!(Flag::Zero | Flag::Carry, cpu.registers.f); // flags are untouched
+
+        cpu.registers.set_hl(0xC000);
+        cpu.mmu.write_byte(0xC000, 0xFF);
+        cpu.execute(Instruction::RES(0, ArithmeticTarget::HLI));
+        assert_eq!(0xFE, cpu.mmu.read_byte(0xC000));
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut cpu = CPU::default();
+        cpu.registers.c = 0xAB;
+        cpu.registers.f.carry = true;
+        cpu.execute(Instruction::SWAP(ArithmeticTarget::C));
+        assert_eq!(0xBA, cpu.registers.c);
+        test_flags!(cpu, false, false, false, false);
+
+        cpu.registers.c = 0x00;
+        cpu.execute(Instruction::SWAP(ArithmeticTarget::C));
+        assert_eq!(0x00, cpu.registers.c);
+        test_flags!(cpu, true, false, false, false);
+    }
+
+    #[test]
+    fn test_cb_rotates_and_shifts() {
+        let mut cpu = CPU::default();
+        cpu.registers.d = 0b1000_0001;
+        cpu.execute(Instruction::RLC(ArithmeticTarget::D));
+        assert_eq!(0b0000_0011, cpu.registers.d);
+        test_flags!(cpu, false, false, false, true);
+
+        cpu.registers.d = 0b0000_0001;
+        cpu.execute(Instruction::RRC(ArithmeticTarget::D));
+        assert_eq!(0b1000_0000, cpu.registers.d);
+        test_flags!(cpu, false, false, false, true);
+
+        cpu.registers.f.carry = false;
+        cpu.registers.d = 0b1000_0000;
+        cpu.execute(Instruction::RL(ArithmeticTarget::D));
+        assert_eq!(0b0000_0000, cpu.registers.d);
+        test_flags!(cpu, true, false, false, true); // unlike RLA, zero is set
+
+        cpu.registers.d = 0b0000_0000;
+        cpu.execute(Instruction::RR(ArithmeticTarget::D));
+        assert_eq!(0b1000_0000, cpu.registers.d);
+        test_flags!(cpu, false, false, false, false);
+
+        cpu.registers.d = 0b1100_0000;
+        cpu.execute(Instruction::SLA(ArithmeticTarget::D));
+        assert_eq!(0b1000_0000, cpu.registers.d);
+        test_flags!(cpu, false, false, false, true);
+
+        cpu.registers.d = 0b1000_0001;
+        cpu.execute(Instruction::SRA(ArithmeticTarget::D));
+        assert_eq!(0b1100_0000, cpu.registers.d);
+        test_flags!(cpu, false, false, false, true);
+
+        cpu.registers.d = 0b1000_0001;
+        cpu.execute(Instruction::SRL(ArithmeticTarget::D));
+        assert_eq!(0b0100_0000, cpu.registers.d);
+        test_flags!(cpu, false, false, false, true);
+
+        cpu.registers.d = 0b0000_0001;
+        cpu.execute(Instruction::SRL(ArithmeticTarget::D));
+        assert_eq!(0b0000_0000, cpu.registers.d);
+        test_flags!(cpu, true, false, false, true);
+    }
+
+    #[test]
+    fn test_cb_rotates_and_shifts_hl_indirect() {
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0x12;
+        cpu.registers.b = 0x34;
+        cpu.registers.set_hl(0xC000);
+
+        cpu.mmu.write_byte(0xC000, 0b1000_0101);
+        assert_eq!(16, cpu.execute(Instruction::RLC(ArithmeticTarget::HLI)));
+        assert_eq!(0b0000_1011, cpu.mmu.read_byte(0xC000));
+        test_flags!(cpu, false, false, false, true);
+
+        cpu.mmu.write_byte(0xC000, 0x00);
+        assert_eq!(16, cpu.execute(Instruction::RLC(ArithmeticTarget::HLI)));
+        assert_eq!(0x00, cpu.mmu.read_byte(0xC000));
+        test_flags!(cpu, true, false, false, false); // zero comes from memory, not A or B
+
+        cpu.mmu.write_byte(0xC000, 0b1000_0010);
+        assert_eq!(16, cpu.execute(Instruction::SRL(ArithmeticTarget::HLI)));
+        assert_eq!(0b0100_0001, cpu.mmu.read_byte(0xC000));
+        test_flags!(cpu, false, false, false, false);
+
+        cpu.mmu.write_byte(0xC000, 0b0000_0001);
+        assert_eq!(16, cpu.execute(Instruction::SRL(ArithmeticTarget::HLI)));
+        assert_eq!(0x00, cpu.mmu.read_byte(0xC000));
+        test_flags!(cpu, true, false, false, true);
+
+        assert_eq!(0x12, cpu.registers.a);
+        assert_eq!(0x34, cpu.registers.b);
+    }
+
+    #[test]
+    fn test_step_prefixed() {
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0x12;
+        cpu.registers.set_hl(0xC000);
+        let program = [
+            0xCB, 0x37, // SWAP A
+            0xCB, 0x7F, // BIT 7,A
+            0xCB, 0xC6, // SET 0,(HL)
+        ];
+        load_program(&mut cpu, 0x0000, &program);
+
+        cpu.step().unwrap();
+        assert_eq!(0x21, cpu.registers.a);
+        assert_eq!(2, cpu.pc);
+
+        cpu.step().unwrap();
+        assert!(cpu.registers.f.zero);
+        assert_eq!(4, cpu.pc);
+
+        cpu.step().unwrap();
+        assert_eq!(0x01, cpu.mmu.read_byte(0xC000));
+        assert_eq!(6, cpu.pc);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ..Default::default()
+        };
+        cpu.registers.set_bc(0xBEEF);
+        cpu.execute(Instruction::PUSH(StackTarget::BC));
+        assert_eq!(0xFFFC, cpu.sp);
+        assert_eq!(0xBE, cpu.mmu.read_byte(0xFFFD)); // high byte goes in first
+        assert_eq!(0xEF, cpu.mmu.read_byte(0xFFFC));
+
+        cpu.execute(Instruction::POP(StackTarget::DE));
+        assert_eq!(0xFFFE, cpu.sp);
+        assert_eq!(0xBEEF, cpu.registers.get_de());
+
+        cpu.registers.set_hl(0x1234);
+        cpu.execute(Instruction::PUSH(StackTarget::HL));
+        cpu.execute(Instruction::PUSH(StackTarget::DE));
+        cpu.execute(Instruction::POP(StackTarget::HL));
+        cpu.execute(Instruction::POP(StackTarget::BC));
+        assert_eq!(0xBEEF, cpu.registers.get_hl());
+        assert_eq!(0x1234, cpu.registers.get_bc());
+        assert_eq!(0xFFFE, cpu.sp);
+    }
+
+    #[test]
+    fn test_pop_af_masks_flags() {
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ..Default::default()
+        };
+        cpu.registers.set_bc(0xBEEF);
+        cpu.execute(Instruction::PUSH(StackTarget::BC));
+        cpu.execute(Instruction::POP(StackTarget::AF));
+        assert_eq!(0xBE, cpu.registers.a);
+        assert_eq!(0xE0, u8::from(cpu.registers.f));
+
+        // bytes written to the stack directly, not through PUSH AF
+        cpu.sp = 0xC000;
+        cpu.mmu.write_byte(0xC000, 0xFF);
+        cpu.mmu.write_byte(0xC001, 0xFF);
+        cpu.execute(Instruction::POP(StackTarget::AF));
+        assert_eq!(0xFF, cpu.registers.a);
+        assert_eq!(0xF0, u8::from(cpu.registers.f));
+        assert_eq!(0xC002, cpu.sp);
+
+        cpu.execute(Instruction::PUSH(StackTarget::AF));
+        assert_eq!(0xF0, cpu.mmu.read_byte(0xC000));
+        assert_eq!(0xFF, cpu.mmu.read_byte(0xC001));
+    }
+
+    fn load_program(cpu: &mut CPU, address: u16, program: &[u8]) {
+        for (i, byte) in program.iter().enumerate() {
+            cpu.mmu.write_byte(address + i as u16, *byte);
+        }
+    }
+
+    #[test]
+    fn test_ld() {
+        let mut cpu = CPU::default();
+        cpu.registers.f = Flag::Zero | Flag::Carry;
+        load_program(
+            &mut cpu,
+            0x0000,
+            &[
+                0x3E, 0x42, // LD A,$42
+                0x41, // LD B,C
+                0x48, // LD C,B
+                0x47, // LD B,A
+                0x21, 0x00, 0xC0, // LD HL,$C000
+                0x77, // LD (HL),A
+                0x36, 0x99, // LD (HL),$99
+                0x5E, // LD E,(HL)
+                0x31, 0xFE, 0xFF, // LD SP,$FFFE
+            ],
+        );
+
+        cpu.step().unwrap();
+        assert_eq!(0x42, cpu.registers.a);
+        assert_eq!(0x0002, cpu.pc); // the immediate is consumed
+
+        cpu.registers.c = 0x07;
+        cpu.step().unwrap();
+        assert_eq!(0x07, cpu.registers.b);
+        assert_eq!(0x07, cpu.registers.c);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(0x42, cpu.registers.b);
+        assert_eq!(0x07, cpu.registers.c);
+        assert_eq!(0x0005, cpu.pc);
+
+        cpu.step().unwrap();
+        assert_eq!(0xC000, cpu.registers.get_hl());
+        assert_eq!(0x0008, cpu.pc);
+
+        cpu.step().unwrap();
+        assert_eq!(0x42, cpu.mmu.read_byte(0xC000));
+
+        cpu.step().unwrap();
+        assert_eq!(0x99, cpu.mmu.read_byte(0xC000));
+        cpu.step().unwrap();
+        assert_eq!(0x99, cpu.registers.e);
+
+        cpu.step().unwrap();
+        assert_eq!(0xFFFE, cpu.sp);
+        assert_eq!(0x000F, cpu.pc);
+        assert_eq!(Flag::Zero | Flag::Carry, cpu.registers.f); // LD never touches flags
+    }
+
+    #[test]
+    fn test_cycles() {
+        let mut cpu = CPU::default();
+        cpu.registers.set_hl(0xC000);
+        load_program(
+            &mut cpu,
+            0x0000,
+            &[
+                0x80, // ADD A,B
+                0x86, // ADD A,(HL)
+                0x34, // INC (HL)
+                0xCB, 0x11, // RL C
+                0xCB, 0x46, // BIT 0,(HL)
+                0xCB, 0x16, // RL (HL)
+                0x3E, 0x01, // LD A,$01
+                0x77, // LD (HL),A
+                0xC5, // PUSH BC
+            ],
+        );
+        let expected = [4, 8, 12, 8, 12, 16, 8, 8, 16];
+        for cycles in expected {
+            assert_eq!(Ok(cycles), cpu.step());
+        }
+        assert_eq!(expected.iter().sum::<u8>() as u64, cpu.cycles);
+    }
+
+    #[test]
+    fn test_conditional_jump_cycles() {
+        let mut cpu = CPU::default();
+        load_program(&mut cpu, 0x0000, &[0x20, 0x02]); // JR NZ,+2
+        cpu.registers.f.zero = true;
+        assert_eq!(Ok(8), cpu.step());
+        assert_eq!(0x0002, cpu.pc);
+
+        cpu.pc = 0x0000;
+        cpu.registers.f.zero = false;
+        assert_eq!(Ok(12), cpu.step());
+        assert_eq!(0x0004, cpu.pc);
+        assert_eq!(20, cpu.cycles);
+
+        load_program(&mut cpu, 0x0004, &[0xDA, 0x00, 0x00]); // JP C,$0000
+        assert_eq!(Ok(12), cpu.step());
+        cpu.pc = 0x0004;
+        cpu.registers.f.carry = true;
+        assert_eq!(Ok(16), cpu.step());
+        assert_eq!(0x0000, cpu.pc);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut cpu = CPU {
+            pc: 0x1234,
+            sp: 0xFFFE,
+            ime: true,
+            ..Default::default()
+        };
+        cpu.mmu.write_byte(0xFFFF, 0x01); // VBlank enabled
+        cpu.mmu.write_byte(0xFF0F, 0x01); // and requested
+
+        assert_eq!(Ok(20), cpu.step());
+        assert_eq!(0x0040, cpu.pc);
+        assert_eq!(0xFFFC, cpu.sp);
+        assert_eq!(0x34, cpu.mmu.read_byte(0xFFFC));
+        assert_eq!(0x12, cpu.mmu.read_byte(0xFFFD));
+        assert_eq!(0x00, cpu.mmu.read_byte(0xFF0F));
+        assert!(!cpu.ime);
+
+        load_program(&mut cpu, 0x0040, &[0xD9]); // RETI
+        assert_eq!(Ok(16), cpu.step());
+        assert_eq!(0x1234, cpu.pc);
+        assert_eq!(0xFFFE, cpu.sp);
+        assert!(cpu.ime);
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ime: true,
+            ..Default::default()
+        };
+        cpu.mmu.write_byte(0xFFFF, 0x1C); // timer, serial and joypad enabled
+        cpu.mmu.write_byte(0xFF0F, 0x17); // VBlank, STAT, timer and joypad requested
+
+        cpu.step().unwrap();
+        assert_eq!(0x0050, cpu.pc); // timer: VBlank and STAT aren't enabled
+        assert_eq!(0x13, cpu.mmu.read_byte(0xFF0F));
+
+        cpu.ime = true;
+        cpu.step().unwrap();
+        assert_eq!(0x0060, cpu.pc);
+        assert_eq!(0x03, cpu.mmu.read_byte(0xFF0F));
+
+        cpu.ime = true;
+        load_program(&mut cpu, 0x0060, &[0x00]); // NOP
+        cpu.step().unwrap();
+        assert_eq!(0x0061, cpu.pc); // nothing left that is enabled
+    }
+
+    #[test]
+    fn test_di_prevents_dispatch() {
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ime: true,
+            ..Default::default()
+        };
+        load_program(&mut cpu, 0x0000, &[0xF3, 0x00]); // DI; NOP
+        cpu.step().unwrap();
+        assert!(!cpu.ime);
+
+        cpu.mmu.write_byte(0xFFFF, 0x01);
+        cpu.mmu.write_byte(0xFF0F, 0x01);
+        assert_eq!(Ok(4), cpu.step());
+        assert_eq!(0x0002, cpu.pc);
+        assert_eq!(0x01, cpu.mmu.read_byte(0xFF0F)); // still requested
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ..Default::default()
+        };
+        load_program(&mut cpu, 0x0000, &[0xFB, 0x00, 0x00]); // EI; NOP; NOP
+        cpu.mmu.write_byte(0xFFFF, 0x01);
+        cpu.mmu.write_byte(0xFF0F, 0x01);
+
+        cpu.step().unwrap(); // EI
+        assert!(!cpu.ime);
+        cpu.step().unwrap(); // the NOP still runs before the interrupt is taken
+        assert_eq!(0x0002, cpu.pc);
+        assert!(cpu.ime);
+        cpu.step().unwrap();
+        assert_eq!(0x0040, cpu.pc);
+        assert_eq!(0x02, cpu.mmu.read_byte(0xFFFC)); // returns to the second NOP
+
+        // EI immediately followed by DI never enables IME
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ..Default::default()
+        };
+        load_program(&mut cpu, 0x0000, &[0xFB, 0xF3, 0x00]); // EI; DI; NOP
+        cpu.mmu.write_byte(0xFFFF, 0x01);
+        cpu.mmu.write_byte(0xFF0F, 0x01);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(!cpu.ime);
+        assert_eq!(0x0003, cpu.pc);
+    }
+
+    #[test]
+    fn test_halt_wake() {
+        // with IME set the handler runs
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ime: true,
+            ..Default::default()
+        };
+        load_program(&mut cpu, 0x0100, &[0x76, 0x00]); // HALT; NOP
+        cpu.pc = 0x0100;
+        cpu.mmu.write_byte(0xFFFF, 0x04); // timer enabled
+        cpu.step().unwrap();
+        assert!(cpu.halted);
+        assert_eq!(Ok(4), cpu.step()); // nothing requested yet, so it stays halted
+        assert_eq!(0x0101, cpu.pc);
+
+        cpu.mmu.write_byte(0xFF0F, 0x04);
+        assert_eq!(Ok(20), cpu.step());
+        assert_eq!(0x0050, cpu.pc);
+        assert_eq!(0x0101, cpu.pop()); // returns past the HALT
+        assert!(!cpu.halted);
+
+        // with IME clear it wakes up but carries on after HALT without servicing
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ..Default::default()
+        };
+        load_program(&mut cpu, 0x0100, &[0x76, 0x04]); // HALT; INC B
+        cpu.pc = 0x0100;
+        cpu.mmu.write_byte(0xFFFF, 0x04);
+        cpu.step().unwrap();
+        assert_eq!(Ok(4), cpu.step());
+        assert!(cpu.halted);
+
+        cpu.mmu.write_byte(0xFF0F, 0x04);
+        cpu.step().unwrap();
+        assert!(!cpu.halted);
+        assert_eq!(0x0102, cpu.pc);
+        assert_eq!(0x01, cpu.registers.b);
+        assert_eq!(0xFFFE, cpu.sp);
+        assert_eq!(0x04, cpu.mmu.read_byte(0xFF0F)); // still requested
+    }
+
+    #[test]
+    fn test_dispatch_cancels_pending_ei() {
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ime: true,
+            ..Default::default()
+        };
+        load_program(&mut cpu, 0x0000, &[0xFB]); // EI while IME is already on
+        load_program(&mut cpu, 0x0040, &[0x00]); // NOP
+        cpu.mmu.write_byte(0xFFFF, 0x01);
+
+        cpu.step().unwrap();
+        assert!(cpu.ime_pending);
+        cpu.mmu.write_byte(0xFF0F, 0x01);
+        assert_eq!(Ok(20), cpu.step());
+        assert_eq!(0x0040, cpu.pc);
+
+        cpu.step().unwrap(); // the handler's first instruction
+        assert_eq!(0x0041, cpu.pc);
+        assert!(!cpu.ime);
+        assert!(!cpu.ime_pending);
+
+        // an interrupt wakes a halted CPU
+        let mut cpu = CPU {
+            sp: 0xFFFE,
+            ime: true,
+            ..Default::default()
+        };
+        load_program(&mut cpu, 0x0000, &[0x76]); // HALT
+        cpu.mmu.write_byte(0xFFFF, 0x01);
+        cpu.step().unwrap();
+        assert!(cpu.halted);
+        cpu.mmu.write_byte(0xFF0F, 0x01);
+        cpu.step().unwrap();
+        assert_eq!(0x0040, cpu.pc);
+        assert!(!cpu.halted);
+    }
+
+    #[test]
+    fn test_jp() {
+        let mut cpu = CPU::default();
+        load_program(&mut cpu, 0x0000, &[0xCA, 0x00, 0x20]); // JP Z,$2000
+        cpu.step().unwrap();
+        assert_eq!(0x0003, cpu.pc); // not taken, but the operand is consumed
+
+        cpu.pc = 0x0000;
+        cpu.registers.f.zero = true;
+        cpu.step().unwrap();
+        assert_eq!(0x2000, cpu.pc);
+
+        load_program(&mut cpu, 0x2000, &[0xC3, 0x34, 0x12]); // JP $1234
+        cpu.step().unwrap();
+        assert_eq!(0x1234, cpu.pc);
+
+        load_program(&mut cpu, 0x1234, &[0xD2, 0x00, 0x30]); // JP NC,$3000
+        cpu.registers.f.carry = true;
+        cpu.step().unwrap();
+        assert_eq!(0x1237, cpu.pc);
+    }
+
+    #[test]
+    fn test_jr() {
+        let mut cpu = CPU::default();
+        load_program(&mut cpu, 0x0100, &[0x20, 0x10]); // JR NZ,+16
+        cpu.pc = 0x0100;
+        cpu.registers.f.zero = true;
+        cpu.step().unwrap();
+        assert_eq!(0x0102, cpu.pc);
+
+        cpu.pc = 0x0100;
+        cpu.registers.f.zero = false;
+        cpu.step().unwrap();
+        assert_eq!(0x0112, cpu.pc);
+
+        load_program(&mut cpu, 0x0112, &[0x18, 0xFE]); // JR -2, i.e. to itself
+        cpu.step().unwrap();
+        assert_eq!(0x0112, cpu.pc);
+    }
+
+    #[test]
+    fn test_jr_loop() {
+        let mut cpu = CPU::default();
+        load_program(
+            &mut cpu,
+            0x0000,
+            &[
+                0x80, // ADD A,B
+                0x05, // DEC B
+                0x20, 0xFC, // JR NZ,-4
+            ],
+        );
+        cpu.registers.b = 10;
+
+        let mut steps = 0;
+        while cpu.pc != 0x0004 {
+            cpu.step().unwrap();
+            steps += 1;
+        }
+        assert_eq!(55, cpu.registers.a); // 10 + 9 + ... + 1
+        assert_eq!(0, cpu.registers.b);
+        assert_eq!(30, steps);
+    }
+
+    #[test]
+    fn test_sum_program() {
+        let mut cpu = CPU::default();
+        load_program(
+            &mut cpu,
+            0x0100,
+            &[
+                0x06, 0x0A, // LD B,$0A
+                0x80, // ADD A,B
+                0x05, // DEC B
+                0x20, 0xFC, // JR NZ,-4
+                0x76, // HALT
+            ],
+        );
+        cpu.pc = 0x0100;
+
+        while !cpu.halted {
+            cpu.step().unwrap();
+        }
+        assert_eq!(55, cpu.registers.a); // 10 + 9 + ... + 1
+        assert_eq!(0, cpu.registers.b);
+        assert_eq!(0x0107, cpu.pc);
+        // LD (8), ten ADD/DEC pairs (80), nine taken JRs (108), the JR that falls through (8)
+        // and HALT (4)
+        assert_eq!(208, cpu.cycles);
+        test_flags!(cpu, true, true, false, false); // from the last DEC B
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let mut cpu = CPU::default();
+        cpu.registers.set_af(0x12F0);
+        cpu.registers.set_bc(0x3456);
+        cpu.registers.set_de(0x789A);
+        cpu.registers.set_hl(0xBCDE);
+        cpu.pc = 0x0150;
+        cpu.sp = 0xFFFE;
+        cpu.halted = true;
+        cpu.ime_pending = true;
+        cpu.cycles = 0x0123_4567_89AB_CDEF;
+        cpu.mmu.write_byte(0x0000, 0x11);
+        cpu.mmu.write_byte(0xC000, 0x22);
+        cpu.mmu.write_byte(0xFFFF, 0x33);
+
+        let state = cpu.save_state();
+        assert_eq!(CPU::STATE_LENGTH, state.len());
+        assert_eq!(b"GBST", &state[0..4]);
+
+        cpu.registers.set_af(0x0000);
+        cpu.registers.set_bc(0x0000);
+        cpu.registers.set_de(0x0000);
+        cpu.registers.set_hl(0x0000);
+        cpu.pc = 0x0000;
+        cpu.sp = 0x0000;
+        cpu.halted = false;
+        cpu.ime = true;
+        cpu.ime_pending = false;
+        cpu.cycles = 0;
+        cpu.mmu.write_byte(0x0000, 0x00);
+        cpu.mmu.write_byte(0xC000, 0x00);
+        cpu.mmu.write_byte(0xFFFF, 0x00);
+
+        assert_eq!(Ok(()), cpu.load_state(&state));
+        assert_eq!(0x12F0, cpu.registers.get_af());
+        assert_eq!(0x3456, cpu.registers.get_bc());
+        assert_eq!(0x789A, cpu.registers.get_de());
+        assert_eq!(0xBCDE, cpu.registers.get_hl());
+        assert_eq!(0x0150, cpu.pc);
+        assert_eq!(0xFFFE, cpu.sp);
+        assert!(cpu.halted);
+        assert!(!cpu.ime);
+        assert!(cpu.ime_pending);
+        assert_eq!(0x0123_4567_89AB_CDEF, cpu.cycles);
+        assert_eq!(0x11, cpu.mmu.read_byte(0x0000));
+        assert_eq!(0x22, cpu.mmu.read_byte(0xC000));
+        assert_eq!(0x33, cpu.mmu.read_byte(0xFFFF));
+        assert_eq!(state, cpu.save_state());
+    }
+
+    #[test]
+    fn test_load_state_errors() {
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0x42;
+        let state = cpu.save_state();
+
+        let mut target = CPU::default();
+        assert_eq!(
+            Err(StateError::Truncated { length: 3 }),
+            target.load_state(&state[..3])
+        );
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(Err(StateError::BadMagic), target.load_state(&bad_magic));
+        assert_eq!(
+            Err(StateError::BadMagic),
+            target.load_state(b"PNG\x0D\x0A\x1A\x0A")
+        );
+
+        let mut bad_version = state.clone();
+        bad_version[4] = 0x99;
+        assert_eq!(
+            Err(StateError::UnsupportedVersion(0x99)),
+            target.load_state(&bad_version)
+        );
+
+        // a real version 2 state is shorter: it was saved before cycles
+        let mut version_2 = state[..CPU::STATE_LENGTH - 8].to_vec();
+        version_2[4] = 0x02;
+        assert_eq!(
+            Err(StateError::UnsupportedVersion(2)),
+            target.load_state(&version_2)
+        );
+
+        assert_eq!(
+            Err(StateError::Truncated {
+                length: state.len() - 1
+            }),
+            target.load_state(&state[..state.len() - 1])
+        );
+        let mut too_long = state.clone();
+        too_long.push(0x00);
+        assert_eq!(
+            Err(StateError::WrongLength {
+                expected: state.len(),
+                actual: state.len() + 1
+            }),
+            target.load_state(&too_long)
+        );
+        assert_eq!(0x00, target.registers.a); // nothing was applied
+
+        assert_eq!(
+            "unsupported save state version 2",
+            StateError::UnsupportedVersion(2).to_string()
+        );
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut mmu = Mmu::default();
+        let program = [
+            0x81, // ADD A,C
+            0x96, // SUB (HL)
+            0x20, 0x12, // JR NZ,$12
+            0xC3, 0x50, 0x01, // JP $0150
+            0xCB, 0x7C, // BIT 7,H
+            0xD3, // not an instruction
+            0x29, // ADD HL,HL
+            0x33, // INC SP
+            0xF5, // PUSH AF
+            0x18, 0xFE, // JR $FE
+            0x7E, // LD A,(HL)
+            0x06, 0x0A, // LD B,$0A
+            0x31, 0xFE, 0xFF, // LD SP,$FFFE
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            mmu.write_byte(0x0100 + i as u16, *byte);
+        }
+
+        let mut address = 0x0100;
+        let mut lines = Vec::new();
+        while address < 0x0100 + program.len() as u16 {
+            let (text, length) = disassemble(&mmu, address);
+            lines.push(text);
+            address += length;
+        }
+        assert_eq!(
+            vec![
+                "ADD A,C",
+                "SUB (HL)",
+                "JR NZ,$12",
+                "JP $0150",
+                "BIT 7,H",
+                "DB $D3",
+                "ADD HL,HL",
+                "INC SP",
+                "PUSH AF",
+                "JR $FE",
+                "LD A,(HL)",
+                "LD B,$0A",
+                "LD SP,$FFFE",
+            ],
+            lines
+        );
+        assert_eq!(0x0100 + program.len() as u16, address);
+    }
+
     #[test]
     fn test_addhl() {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x0000);
         cpu.execute(Instruction::ADDHL(ADDHLTarget::HL));
         assert_eq!(0x0000, cpu.registers.get_hl());
-        test_flags!(cpu, true, false, false, false);
+        test_flags!(cpu, false, false, false, false);
 
         cpu.registers.set_hl(0x0001);
         cpu.execute(Instruction::ADDHL(ADDHLTarget::HL));
@@ -409,6 +2479,25 @@ mod tests {
         test_flags!(cpu, false, false, true, false);
     }
 
+    #[test]
+    fn test_addhl_preserves_zero() {
+        let mut cpu = CPU::default();
+        cpu.registers.f.zero = true;
+        cpu.registers.f.subtract = true;
+        cpu.registers.set_hl(0x0800);
+        cpu.registers.set_bc(0x0800);
+        cpu.execute(Instruction::ADDHL(ADDHLTarget::BC));
+        assert_eq!(0x1000, cpu.registers.get_hl());
+        test_flags!(cpu, true, false, true, false);
+
+        cpu.registers.f.zero = false;
+        cpu.registers.set_hl(0x8000);
+        cpu.registers.set_de(0x8000);
+        cpu.execute(Instruction::ADDHL(ADDHLTarget::DE));
+        assert_eq!(0x0000, cpu.registers.get_hl());
+        test_flags!(cpu, false, false, false, true); // a zero result doesn't set it either
+    }
+
     #[test]
     fn test_adc() {
         let mut cpu = CPU::default();
@@ -421,16 +2510,23 @@ mod tests {
         cpu.registers.a = 0xFF;
         cpu.registers.d = 0x01;
         cpu.execute(Instruction::ADC(ArithmeticTarget::D));
-        assert_eq!(0x01, cpu.registers.a);
-        test_flags!(cpu, false, false, true, true);
+        assert_eq!(0x00, cpu.registers.a);
+        test_flags!(cpu, true, false, true, true);
 
         cpu.registers.a = 0xFF;
         cpu.registers.d = 0x01;
         cpu.registers.f.carry = true;
         cpu.execute(Instruction::ADC(ArithmeticTarget::D));
-        assert_eq!(0x02, cpu.registers.a);
+        assert_eq!(0x01, cpu.registers.a);
         test_flags!(cpu, false, false, true, true);
 
+        cpu.registers.a = 0xFF;
+        cpu.registers.d = 0x00;
+        cpu.registers.f.carry = true;
+        cpu.execute(Instruction::ADC(ArithmeticTarget::D));
+        assert_eq!(0x00, cpu.registers.a); // only the incoming carry overflows
+        test_flags!(cpu, true, false, true, true);
+
         cpu.registers.a = 0x8F;
         cpu.registers.b = 0x01;
         cpu.registers.f.carry = true;
@@ -439,6 +2535,42 @@ mod tests {
         test_flags!(cpu, false, false, true, false);
     }
 
+    #[test]
+    fn test_half_carry_boundaries() {
+        type Operation = fn(ArithmeticTarget) -> Instruction;
+        let cases: [(Operation, u8, u8, u8, FlagsRegister); 8] = [
+            (Instruction::ADD, 0x0F, 0x01, 0x10, Flag::HalfCarry.into()),
+            (Instruction::ADD, 0x08, 0x08, 0x10, Flag::HalfCarry.into()),
+            (Instruction::ADC, 0x0F, 0x01, 0x10, Flag::HalfCarry.into()),
+            (Instruction::ADC, 0x08, 0x08, 0x10, Flag::HalfCarry.into()),
+            (
+                Instruction::SUB,
+                0x10,
+                0x01,
+                0x0F,
+                Flag::Subtract | Flag::HalfCarry,
+            ),
+            (Instruction::SUB, 0xF0, 0x10, 0xE0, Flag::Subtract.into()),
+            (
+                Instruction::SBC,
+                0x10,
+                0x01,
+                0x0F,
+                Flag::Subtract | Flag::HalfCarry,
+            ),
+            (Instruction::SBC, 0xF0, 0x10, 0xE0, Flag::Subtract.into()),
+        ];
+
+        for (instruction, a, value, result, flags) in cases {
+            let mut cpu = CPU::default();
+            cpu.registers.a = a;
+            cpu.registers.b = value;
+            cpu.execute(instruction(ArithmeticTarget::B));
+            assert_eq!(result, cpu.registers.a, "{a:#04X}, {value:#04X}");
+            assert_eq!(flags, cpu.registers.f, "{a:#04X}, {value:#04X}");
+        }
+    }
+
     #[test]
     fn test_sub() {
         let mut cpu = CPU::default();
@@ -465,6 +2597,18 @@ mod tests {
         cpu.execute(Instruction::SUB(ArithmeticTarget::C));
         assert_eq!(0xEF, cpu.registers.a);
         test_flags!(cpu, false, true, true, true);
+
+        cpu.registers.a = 0x10;
+        cpu.registers.c = 0x01;
+        cpu.execute(Instruction::SUB(ArithmeticTarget::C));
+        assert_eq!(0x0F, cpu.registers.a);
+        test_flags!(cpu, false, true, true, false);
+
+        cpu.registers.a = 0x1F;
+        cpu.registers.c = 0x0F;
+        cpu.execute(Instruction::SUB(ArithmeticTarget::C));
+        assert_eq!(0x10, cpu.registers.a);
+        test_flags!(cpu, false, true, false, false);
     }
 
     #[test]
@@ -479,8 +2623,51 @@ mod tests {
         cpu.registers.a = 0x20;
         cpu.registers.c = 0x31;
         cpu.execute(Instruction::SBC(ArithmeticTarget::C));
-        assert_eq!(0xEE, cpu.registers.a);
+        assert_eq!(0xEF, cpu.registers.a);
         test_flags!(cpu, false, true, true, true);
+
+        cpu.registers.a = 0x20;
+        cpu.registers.c = 0x01;
+        cpu.execute(Instruction::SBC(ArithmeticTarget::C)); // carry set by the previous SBC
+        assert_eq!(0x1E, cpu.registers.a);
+        test_flags!(cpu, false, true, true, false);
+
+        cpu.registers.a = 0x10;
+        cpu.registers.c = 0x00;
+        cpu.registers.f.carry = true;
+        cpu.execute(Instruction::SBC(ArithmeticTarget::C));
+        assert_eq!(0x0F, cpu.registers.a); // the carry alone borrows from bit 4
+        test_flags!(cpu, false, true, true, false);
+    }
+
+    #[test]
+    fn test_sbc_exhaustive() {
+        let mut cpu = CPU::default();
+        for carry in [false, true] {
+            for a in 0..=0xFFu8 {
+                for value in 0..=0xFFu8 {
+                    let expected = a as i16 - value as i16 - carry as i16;
+                    let expected_half = (a & 0xF) as i16 - (value & 0xF) as i16 - carry as i16;
+
+                    cpu.registers.a = a;
+                    cpu.registers.b = value;
+                    cpu.registers.f.carry = carry;
+                    cpu.execute(Instruction::SBC(ArithmeticTarget::B));
+
+                    assert_eq!(
+                        expected as u8, cpu.registers.a,
+                        "{a:#04X} - {value:#04X} - {carry}"
+                    );
+                    test_flags!(
+                        cpu,
+                        expected as u8 == 0,
+                        true,
+                        expected_half < 0,
+                        expected < 0
+                    );
+                }
+            }
+        }
     }
 
     #[test]
@@ -527,6 +2714,24 @@ mod tests {
         cpu.execute(Instruction::CP(ArithmeticTarget::C));
         assert_eq!(0x00, cpu.registers.a);
         test_flags!(cpu, false, true, true, true);
+
+        cpu.registers.a = 0x10;
+        cpu.registers.b = 0x01;
+        cpu.execute(Instruction::CP(ArithmeticTarget::B));
+        assert_eq!(0x10, cpu.registers.a);
+        test_flags!(cpu, false, true, true, false); // borrow from bit 4 only
+
+        cpu.registers.a = 0x00;
+        cpu.registers.b = 0x10;
+        cpu.execute(Instruction::CP(ArithmeticTarget::B));
+        assert_eq!(0x00, cpu.registers.a);
+        test_flags!(cpu, false, true, false, true); // A < operand, low nibbles equal
+
+        cpu.registers.a = 0x10;
+        cpu.registers.b = 0x10;
+        cpu.execute(Instruction::CP(ArithmeticTarget::B));
+        assert_eq!(0x10, cpu.registers.a);
+        test_flags!(cpu, true, true, false, false);
     }
 
     #[test]
@@ -540,12 +2745,14 @@ mod tests {
         cpu.registers.a = 0xFF;
         cpu.execute(Instruction::INC(ArithmeticTarget::A));
         assert_eq!(0x00, cpu.registers.a);
-        test_flags!(cpu, true, false, true, true);
+        test_flags!(cpu, true, false, true, false); // wrapping doesn't set carry
 
         cpu.registers.c = 0xFF;
+        cpu.registers.f.carry = true;
         cpu.execute(Instruction::INC(ArithmeticTarget::C));
         assert_eq!(0x00, cpu.registers.c);
-        test_flags!(cpu, true, false, true, true);
+        test_flags!(cpu, true, false, true, true); // nor clear it
+        cpu.registers.f.carry = false;
 
         cpu.registers.c = 0x0F;
         cpu.execute(Instruction::INC(ArithmeticTarget::C));
@@ -564,7 +2771,7 @@ mod tests {
         cpu.registers.a = 0x00;
         cpu.execute(Instruction::DEC(ArithmeticTarget::A));
         assert_eq!(0xFF, cpu.registers.a);
-        test_flags!(cpu, false, true, true, true);
+        test_flags!(cpu, false, true, true, false); // wrapping doesn't set carry
 
         cpu.registers.c = 0x10;
         cpu.execute(Instruction::DEC(ArithmeticTarget::C));
@@ -572,7 +2779,84 @@ mod tests {
         test_flags!(cpu, false, true, true, false);
     }
 
+    fn set_register(cpu: &mut CPU, target: ArithmeticTarget, value: u8) {
+        match target {
+            ArithmeticTarget::A => cpu.registers.a = value,
+            ArithmeticTarget::B => cpu.registers.b = value,
+            ArithmeticTarget::C => cpu.registers.c = value,
+            ArithmeticTarget::D => cpu.registers.d = value,
+            ArithmeticTarget::E => cpu.registers.e = value,
+            ArithmeticTarget::H => cpu.registers.h = value,
+            ArithmeticTarget::L => cpu.registers.l = value,
+            ArithmeticTarget::HLI => cpu.mmu.write_byte(cpu.registers.get_hl(), value),
+        }
+    }
+
+    #[test]
+    fn test_inc_dec_every_register() {
+        use ArithmeticTarget::*;
+        let targets = [A, B, C, D, E, H, L];
+
+        for target in targets {
+            let mut cpu = CPU::default();
+
+            set_register(&mut cpu, target, 0x0F);
+            cpu.execute(Instruction::INC(target));
+            assert_eq!(0x10, cpu.get_value_from_target(target), "INC {target:?}");
+            test_flags!(cpu, false, false, true, false);
+
+            set_register(&mut cpu, target, 0x10);
+            cpu.execute(Instruction::DEC(target));
+            assert_eq!(0x0F, cpu.get_value_from_target(target), "DEC {target:?}");
+            test_flags!(cpu, false, true, true, false);
+
+            set_register(&mut cpu, target, 0x0F);
+            cpu.execute(Instruction::DEC(target));
+            assert_eq!(0x0E, cpu.get_value_from_target(target), "DEC {target:?}");
+            test_flags!(cpu, false, true, false, false);
+
+            set_register(&mut cpu, target, 0x00);
+            cpu.registers.f.carry = true;
+            cpu.execute(Instruction::DEC(target));
+            assert_eq!(0xFF, cpu.get_value_from_target(target), "DEC {target:?}");
+            test_flags!(cpu, false, true, true, true);
+
+            set_register(&mut cpu, target, 0x00);
+            for other in targets.iter().filter(|&&other| other != target) {
+                assert_eq!(
+                    0x00,
+                    cpu.get_value_from_target(*other),
+                    "{target:?} wrote {other:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_inc16_dec16() {
+        let mut cpu = CPU::default();
+        cpu.registers.f = Flag::Subtract | Flag::Carry;
+
+        cpu.registers.set_hl(0xFFFF);
+        cpu.execute(Instruction::INC16(WordTarget::HL));
+        assert_eq!(0x0000, cpu.registers.get_hl());
+        assert_eq!(Flag::Subtract | Flag::Carry, cpu.registers.f);
+
+        cpu.execute(Instruction::DEC16(WordTarget::SP));
+        assert_eq!(0xFFFF, cpu.sp);
+        assert_eq!(Flag::Subtract | Flag::Carry, cpu.registers.f);
+
+        cpu.registers.set_bc(0x00FF);
+        cpu.execute(Instruction::INC16(WordTarget::BC));
+        assert_eq!(0x0100, cpu.registers.get_bc()); // carries into the high register
+        cpu.registers.set_de(0x0100);
+        cpu.execute(Instruction::DEC16(WordTarget::DE));
+        assert_eq!(0x00FF, cpu.registers.get_de());
+        assert_eq!(Flag::Subtract | Flag::Carry, cpu.registers.f);
+    }
+
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_ccf() {
         let mut cpu = CPU::default();
         cpu.execute(Instruction::CCF());
@@ -583,6 +2867,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_scf() {
         let mut cpu = CPU::default();
         cpu.execute(Instruction::SCF());
@@ -593,6 +2878,51 @@ mod tests {
     }
 
     #[test]
+    fn test_scf_ccf_preserve_zero() {
+        let mut cpu = CPU::default();
+        cpu.registers.f = Flag::Zero | Flag::Subtract | Flag::HalfCarry;
+        cpu.execute(Instruction::SCF());
+        assert_eq!(Flag::Zero | Flag::Carry, cpu.registers.f);
+
+        cpu.execute(Instruction::CCF());
+        assert_eq!(FlagsRegister::from(Flag::Zero), cpu.registers.f);
+
+        cpu.registers.f = Flag::Subtract | Flag::HalfCarry;
+        cpu.execute(Instruction::CCF());
+        assert_eq!(FlagsRegister::from(Flag::Carry), cpu.registers.f);
+    }
+
+    #[test]
+    fn test_cpl() {
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0b1010_0101;
+        cpu.registers.f = Flag::Zero | Flag::Carry;
+        cpu.execute(Instruction::CPL());
+        assert_eq!(0b0101_1010, cpu.registers.a);
+        test_flags!(cpu, true, true, true, true);
+
+        cpu.registers.f = FlagsRegister::default();
+        cpu.execute(Instruction::CPL());
+        assert_eq!(0b1010_0101, cpu.registers.a);
+        test_flags!(cpu, false, true, true, false);
+    }
+
+    #[test]
+    fn test_nop_halt() {
+        let mut cpu = CPU::default();
+        load_program(&mut cpu, 0x0000, &[0x00, 0x76]); // NOP; HALT
+        cpu.step().unwrap();
+        assert_eq!(0x0001, cpu.pc);
+        assert!(!cpu.halted);
+        assert_eq!(FlagsRegister::default(), cpu.registers.f);
+
+        cpu.step().unwrap();
+        assert_eq!(0x0002, cpu.pc);
+        assert!(cpu.halted);
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_rra() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b0000_0001;
@@ -616,9 +2946,16 @@ mod tests {
         cpu.execute(Instruction::RRA());
         assert_eq!(0b0000_1000, cpu.registers.a);
         assert_eq!(false, cpu.registers.f.carry);
+
+        cpu.registers.f.carry = true;
+        cpu.registers.a = 0b0000_0001;
+        cpu.execute(Instruction::RRA());
+        assert_eq!(0b1000_0000, cpu.registers.a); // old carry goes into bit 7
+        assert_eq!(true, cpu.registers.f.carry);
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_rla() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b0000_0001;
@@ -636,21 +2973,72 @@ mod tests {
         cpu.execute(Instruction::RLA());
         assert_eq!(0b0000_0001, cpu.registers.a);
         assert_eq!(true, cpu.registers.f.carry);
+
+        cpu.registers.f.carry = true;
+        cpu.registers.a = 0b0000_0000;
+        cpu.execute(Instruction::RLA());
+        assert_eq!(0b0000_0001, cpu.registers.a); // old carry goes into bit 0
+        assert_eq!(false, cpu.registers.f.carry);
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_rrca() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b0000_0001;
         cpu.execute(Instruction::RRCA());
-        println!("{:08b}", cpu.registers.a);
         assert_eq!(0b1000_0000, cpu.registers.a);
-        assert_eq!(false, cpu.registers.f.carry);
+        assert_eq!(true, cpu.registers.f.carry); // bit 0 goes to both bit 7 and carry
 
         cpu.registers.f.carry = true;
         cpu.registers.a = 0b1000_0000;
         cpu.execute(Instruction::RRCA());
-        assert_eq!(0b0100_0000, cpu.registers.a);
-        assert_eq!(true, cpu.registers.f.carry);
+        assert_eq!(0b0100_0000, cpu.registers.a); // old carry is not rotated in
+        assert_eq!(false, cpu.registers.f.carry);
+    }
+
+    #[test]
+    fn test_accumulator_rotate_flags() {
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0x80;
+        cpu.registers.f = Flag::Zero | Flag::Subtract | Flag::HalfCarry;
+        cpu.execute(Instruction::RLCA());
+        assert_eq!(0x01, cpu.registers.a);
+        assert_eq!(FlagsRegister::from(Flag::Carry), cpu.registers.f);
+
+        cpu.registers.a = 0x00;
+        cpu.registers.f = Flag::Carry.into();
+        cpu.execute(Instruction::RRA());
+        assert_eq!(0x80, cpu.registers.a); // the preset carry feeds bit 7
+        assert_eq!(FlagsRegister::default(), cpu.registers.f);
+
+        // a zero result still leaves zero clear
+        for instruction in [
+            Instruction::RLCA(),
+            Instruction::RRCA(),
+            Instruction::RLA(),
+            Instruction::RRA(),
+        ] {
+            cpu.registers.a = 0x00;
+            cpu.registers.f = Flag::Zero | Flag::Subtract | Flag::HalfCarry;
+            cpu.execute(instruction);
+            assert_eq!(0x00, cpu.registers.a);
+            assert_eq!(FlagsRegister::default(), cpu.registers.f);
+        }
+    }
+
+    #[test]
+    fn test_rlca() {
+        let mut cpu = CPU::default();
+        cpu.registers.a = 0b1000_0000;
+        cpu.execute(Instruction::RLCA());
+        assert_eq!(0b0000_0001, cpu.registers.a);
+        assert!(cpu.registers.f.carry); // bit 7 goes to both bit 0 and carry
+
+        cpu.registers.f.carry = true;
+        cpu.registers.a = 0b0000_0001;
+        cpu.execute(Instruction::RLCA());
+        assert_eq!(0b0000_0010, cpu.registers.a); // old carry is not rotated in
+        assert!(!cpu.registers.f.carry);
     }
 }